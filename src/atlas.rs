@@ -0,0 +1,371 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use glam::{Vec2, Vec4};
+use image::{Rgba, RgbaImage};
+use crate::block::{BlockRegistry, Face};
+use crate::mipmap;
+
+const MISSING_TEXTURE_SIZE: u32 = 16;
+
+// How block textures reach the GPU. Both end up in an array texture, the atlas as its only layer.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TextureLayout {
+    // All textures packed into one image, faces wrap inside their tile
+    Atlas,
+    // Every texture in its own layer, faces wrap around the whole layer and nothing can bleed in
+    #[default]
+    Array
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct AtlasConfig {
+    // Pixels around every tile filled with the tile wrapped around, so filtering and smaller mip levels near
+    // the edge of a tile sample the tile itself instead of its neighbours
+    pub padding: u32
+}
+
+impl Default for AtlasConfig {
+    fn default() -> Self {
+        Self {
+            padding: 4
+        }
+    }
+}
+
+impl AtlasConfig {
+    // Mip levels that keep at least a texel of margin around every tile, smaller ones would blend neighbours
+    pub fn mip_levels(&self) -> u32 {
+        mipmap::level_count(self.padding, self.padding)
+    }
+}
+
+// Area of a tile in normalized texture coordinates
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct UvRect {
+    pub min: Vec2,
+    pub max: Vec2
+}
+
+impl UvRect {
+    // The whole texture, what faces used before there was an atlas
+    pub const FULL: Self = Self { min: Vec2::ZERO, max: Vec2::ONE };
+
+    pub fn size(&self) -> Vec2 {
+        self.max - self.min
+    }
+
+    // Packed the way the vertices carry it, the minimum in xy and the size in zw
+    pub fn to_vec4(&self) -> Vec4 {
+        self.min.extend(self.size().x).extend(self.size().y)
+    }
+}
+
+// All block textures packed into one image. Names are the file names without extension, names that are not
+// in the atlas map to a checkerboard tile.
+pub struct TextureAtlas {
+    image: RgbaImage,
+    rects: HashMap<String, UvRect>,
+    missing: UvRect
+}
+
+impl TextureAtlas {
+    pub fn load(directory: impl AsRef<Path>, config: AtlasConfig) -> Self {
+        Self::from_images(load_images(directory.as_ref()), config)
+    }
+
+    pub fn from_images(mut images: Vec<(String, RgbaImage)>, config: AtlasConfig) -> Self {
+        // Tallest first keeps the shelves tight, names make the layout independent of the directory order
+        images.sort_by(|(a_name, a), (b_name, b)| b.height().cmp(&a.height()).then_with(|| a_name.cmp(b_name)));
+        images.push((String::new(), missing_texture()));
+
+        let padding = config.padding;
+        let cell = |image: &RgbaImage| (image.width() + 2 * padding, image.height() + 2 * padding);
+        let area = images.iter().map(|(_, image)| cell(image).0 as u64 * cell(image).1 as u64).sum::<u64>();
+        let widest = images.iter().map(|(_, image)| cell(image).0).max().unwrap();
+        let width = ((area as f64).sqrt().ceil() as u32).max(widest).next_power_of_two();
+
+        // Shelf packing, left to right and wrapping to a new row when the current one is full
+        let mut placements = Vec::with_capacity(images.len());
+        let (mut x, mut y, mut row_height) = (0, 0, 0);
+        for (_, image) in images.iter() {
+            let (cell_width, cell_height) = cell(image);
+            if x + cell_width > width {
+                x = 0;
+                y += row_height;
+                row_height = 0;
+            }
+            placements.push((x, y));
+            x += cell_width;
+            row_height = row_height.max(cell_height);
+        }
+        let height = (y + row_height).next_power_of_two();
+
+        let mut atlas = RgbaImage::new(width, height);
+        let mut rects = HashMap::new();
+        let mut missing = UvRect::FULL;
+        for ((name, image), (x, y)) in images.iter().zip(placements) {
+            let (cell_width, cell_height) = cell(image);
+            for cell_y in 0..cell_height {
+                for cell_x in 0..cell_width {
+                    let source_x = (cell_x as i64 - padding as i64).rem_euclid(image.width() as i64) as u32;
+                    let source_y = (cell_y as i64 - padding as i64).rem_euclid(image.height() as i64) as u32;
+                    atlas.put_pixel(x + cell_x, y + cell_y, *image.get_pixel(source_x, source_y));
+                }
+            }
+
+            let size = Vec2::new(width as f32, height as f32);
+            let min = Vec2::new((x + padding) as f32, (y + padding) as f32);
+            let rect = UvRect {
+                min: min / size,
+                max: (min + Vec2::new(image.width() as f32, image.height() as f32)) / size
+            };
+
+            if name.is_empty() {
+                missing = rect;
+            } else {
+                rects.insert(name.clone(), rect);
+            }
+        }
+
+        Self {
+            image: atlas,
+            rects,
+            missing
+        }
+    }
+
+    pub fn image(&self) -> &RgbaImage {
+        &self.image
+    }
+
+    pub fn get(&self, name: &str) -> Option<UvRect> {
+        self.rects.get(name).copied()
+    }
+
+    pub fn get_or_missing(&self, name: &str) -> UvRect {
+        self.get(name).unwrap_or(self.missing)
+    }
+}
+
+// Block textures as the layers of an array texture, which needs all of them to have the same size. Layers
+// are ordered by name, with the checkerboard for missing textures last.
+pub struct TextureLayers {
+    images: Vec<RgbaImage>,
+    layers: HashMap<String, u32>,
+    missing: u32
+}
+
+impl TextureLayers {
+    pub fn load(directory: impl AsRef<Path>) -> Self {
+        let directory = directory.as_ref();
+        Self::from_images(load_images(directory)).unwrap_or_else(|error| panic!("Failed to load {}: {}", directory.display(), error))
+    }
+
+    pub fn from_images(mut images: Vec<(String, RgbaImage)>) -> Result<Self, String> {
+        images.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let (width, height) = images.first().map_or((MISSING_TEXTURE_SIZE, MISSING_TEXTURE_SIZE), |(_, image)| image.dimensions());
+
+        let mut layers = HashMap::new();
+        let mut layer_images = Vec::with_capacity(images.len() + 1);
+        for (name, image) in images {
+            if image.dimensions() != (width, height) {
+                return Err(format!("Texture {} is {}x{}, all layers have to be {}x{}", name, image.width(), image.height(), width, height));
+            }
+            layers.insert(name, layer_images.len() as u32);
+            layer_images.push(image);
+        }
+
+        let missing = layer_images.len() as u32;
+        let checkerboard = missing_texture();
+        layer_images.push(RgbaImage::from_fn(width, height, |x, y| {
+            *checkerboard.get_pixel(x * MISSING_TEXTURE_SIZE / width, y * MISSING_TEXTURE_SIZE / height)
+        }));
+
+        Ok(Self {
+            images: layer_images,
+            layers,
+            missing
+        })
+    }
+
+    pub fn images(&self) -> &[RgbaImage] {
+        &self.images
+    }
+
+    pub fn get(&self, name: &str) -> Option<u32> {
+        self.layers.get(name).copied()
+    }
+
+    pub fn get_or_missing(&self, name: &str) -> u32 {
+        self.get(name).unwrap_or(self.missing)
+    }
+}
+
+// Where a face finds its texture, a rectangle inside an array layer
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FaceUv {
+    pub rect: UvRect,
+    pub layer: u32
+}
+
+impl FaceUv {
+    pub const FULL: Self = Self { rect: UvRect::FULL, layer: 0 };
+}
+
+// Texture of every face of every block, looked up by the mesher
+#[derive(Debug, Default)]
+pub struct BlockUvs {
+    faces: Vec<[FaceUv; 6]>
+}
+
+impl BlockUvs {
+    pub fn new(registry: &BlockRegistry, atlas: &TextureAtlas) -> Self {
+        Self::resolve(registry, |name| FaceUv {
+            rect: name.map_or(atlas.missing, |name| atlas.get_or_missing(name)),
+            layer: 0
+        })
+    }
+
+    pub fn from_layers(registry: &BlockRegistry, layers: &TextureLayers) -> Self {
+        Self::resolve(registry, |name| FaceUv {
+            rect: UvRect::FULL,
+            layer: name.map_or(layers.missing, |name| layers.get_or_missing(name))
+        })
+    }
+
+    fn resolve(registry: &BlockRegistry, lookup: impl Fn(Option<&str>) -> FaceUv) -> Self {
+        let mut faces = Vec::new();
+        for block in registry.iter() {
+            let index = block.id as usize;
+            if faces.len() <= index {
+                faces.resize(index + 1, [lookup(None); 6]);
+            }
+            faces[index] = Face::ALL.map(|face| lookup(block.textures.get(face)));
+        }
+
+        Self {
+            faces
+        }
+    }
+
+    pub fn get(&self, block: u16, face: Face) -> FaceUv {
+        self.faces.get(block as usize).map_or(FaceUv::FULL, |faces| faces[face as usize])
+    }
+}
+
+// Every png in the directory, named after the file without its extension
+fn load_images(directory: &Path) -> Vec<(String, RgbaImage)> {
+    let entries = fs::read_dir(directory).unwrap_or_else(|_| panic!("Failed to read {}", directory.display()));
+
+    let mut images = Vec::new();
+    for entry in entries {
+        let path = entry.unwrap_or_else(|error| panic!("Failed to read {}: {}", directory.display(), error)).path();
+        if path.extension().is_none_or(|extension| extension != "png") {
+            continue;
+        }
+
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        let image = image::open(&path).unwrap_or_else(|_| panic!("Failed to load {}", path.display())).to_rgba8();
+        images.push((name, image));
+    }
+    images
+}
+
+fn missing_texture() -> RgbaImage {
+    RgbaImage::from_fn(MISSING_TEXTURE_SIZE, MISSING_TEXTURE_SIZE, |x, y| {
+        if (x < MISSING_TEXTURE_SIZE / 2) == (y < MISSING_TEXTURE_SIZE / 2) {
+            Rgba([255, 0, 255, 255])
+        } else {
+            Rgba([0, 0, 0, 255])
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(width: u32, height: u32, value: u8) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, y| Rgba([value, x as u8, y as u8, 255]))
+    }
+
+    fn pixel_at(atlas: &TextureAtlas, uv: Vec2) -> Rgba<u8> {
+        let size = Vec2::new(atlas.image().width() as f32, atlas.image().height() as f32);
+        let pixel = (uv * size).floor();
+        *atlas.image().get_pixel(pixel.x as u32, pixel.y as u32)
+    }
+
+    #[test]
+    fn packs_tiles_without_overlap() {
+        let images = (0..20).map(|index| (format!("tile_{}", index), tile(16, 8 + index % 3 * 8, index as u8))).collect();
+        let atlas = TextureAtlas::from_images(images, AtlasConfig::default());
+
+        assert!(atlas.image().width().is_power_of_two() && atlas.image().height().is_power_of_two());
+        let rects = (0..20).map(|index| atlas.get(&format!("tile_{}", index)).unwrap()).collect::<Vec<_>>();
+        for (index, rect) in rects.iter().enumerate() {
+            // Every tile is stored at full resolution where its rectangle says
+            let texel = Vec2::new(1.0 / atlas.image().width() as f32, 1.0 / atlas.image().height() as f32);
+            assert_eq!(pixel_at(&atlas, rect.min + texel * 0.5), Rgba([index as u8, 0, 0, 255]));
+            assert_eq!(rect.size() / texel, Vec2::new(16.0, (8 + index % 3 * 8) as f32));
+
+            for other in rects[index + 1..].iter() {
+                let overlap = rect.min.cmplt(other.max).all() && other.min.cmplt(rect.max).all();
+                assert!(!overlap, "{:?} overlaps {:?}", rect, other);
+            }
+        }
+    }
+
+    #[test]
+    fn padding_repeats_the_tile() {
+        let atlas = TextureAtlas::from_images(vec![("a".to_owned(), tile(16, 16, 1)), ("b".to_owned(), tile(16, 16, 2))], AtlasConfig { padding: 2 });
+        let rect = atlas.get("a").unwrap();
+        let texel = Vec2::new(1.0 / atlas.image().width() as f32, 1.0 / atlas.image().height() as f32);
+
+        // The margin left of the tile continues with its right edge, the one above with its bottom edge
+        assert_eq!(pixel_at(&atlas, rect.min - texel * 0.5), Rgba([1, 15, 15, 255]));
+        assert_eq!(pixel_at(&atlas, rect.min + Vec2::new(-1.5, 0.5) * texel), Rgba([1, 14, 0, 255]));
+        assert_eq!(pixel_at(&atlas, rect.max + texel * 0.5), Rgba([1, 0, 0, 255]));
+    }
+
+    #[test]
+    fn every_shipped_block_face_has_a_texture() {
+        let registry = BlockRegistry::load("blocks.ron");
+        let atlas = TextureAtlas::load("textures", AtlasConfig::default());
+        let uvs = BlockUvs::new(&registry, &atlas);
+
+        for block in registry.iter().filter(|block| block.id != 0) {
+            for face in Face::ALL {
+                let name = block.textures.get(face).unwrap();
+                assert_eq!(atlas.get(name), Some(uvs.get(block.id, face).rect), "{} has no texture {}", block.name, name);
+            }
+        }
+
+        let grass = registry.id("grass").unwrap();
+        assert_ne!(uvs.get(grass, Face::Top), uvs.get(grass, Face::North));
+        assert_eq!(atlas.get_or_missing("nothing"), uvs.get(0, Face::Top).rect);
+    }
+
+    #[test]
+    fn layers_follow_the_texture_names() {
+        let registry = BlockRegistry::load("blocks.ron");
+        let layers = TextureLayers::load("textures");
+        let uvs = BlockUvs::from_layers(&registry, &layers);
+
+        let grass = registry.id("grass").unwrap();
+        let top = uvs.get(grass, Face::Top);
+        assert_eq!(top, FaceUv { rect: UvRect::FULL, layer: layers.get("grass_top").unwrap() });
+        assert_eq!(uvs.get(grass, Face::Bottom).layer, layers.get("dirt").unwrap());
+        assert_eq!(layers.get("cobblestone"), Some(0));
+
+        // The missing texture is scaled up to the size of the other layers
+        assert_eq!(uvs.get(0, Face::Top).layer as usize, layers.images().len() - 1);
+        assert!(layers.images().iter().all(|image| image.dimensions() == layers.images()[0].dimensions()));
+    }
+
+    #[test]
+    fn layers_must_share_a_size() {
+        let images = vec![("a".to_owned(), tile(16, 16, 1)), ("b".to_owned(), tile(32, 32, 2))];
+        assert!(TextureLayers::from_images(images).is_err());
+    }
+}
@@ -0,0 +1,200 @@
+use crate::block::BlockRegistry;
+use crate::tick::{TickContext, TickHandler, TickScheduler};
+use crate::world::{Position, BLOCK_TYPE_AIR};
+
+// Light a block gets from the sky or from the blocks around it, whichever is brighter
+fn light_at(context: &TickContext, position: &Position) -> u8 {
+    context.world().get_block_light(position).max(context.world().get_sky_light(position))
+}
+
+// Blocks like sand fall one block per update while there is air or fluid below them
+pub struct Gravity {
+    pub fall_delay: u64
+}
+
+impl TickHandler for Gravity {
+    fn scheduled_tick(&self, context: &mut TickContext, position: &Position, block: u16) {
+        let below = position.offset(0, -1, 0);
+        let below_block = context.get_block(&below);
+        if !context.is_loaded(&below) || below_block != BLOCK_TYPE_AIR && context.registry().fluid(below_block).is_none() {
+            return;
+        }
+
+        // The block lands below first, so it schedules its next step before the gap above wakes anything up
+        context.set_block(&below, block);
+        context.set_block(position, BLOCK_TYPE_AIR);
+    }
+
+    fn neighbour_changed(&self, context: &mut TickContext, position: &Position, _block: u16) {
+        context.schedule(position, self.fall_delay);
+    }
+}
+
+// Grass spreads to dirt nearby that has enough light and nothing opaque on top, and dies back to dirt once
+// something opaque covers it
+pub struct GrassSpread {
+    pub dirt: u16,
+    pub min_light: u8
+}
+
+impl TickHandler for GrassSpread {
+    fn random_tick(&self, context: &mut TickContext, position: &Position, block: u16) {
+        let registry = context.registry();
+        let above = position.offset(0, 1, 0);
+        if registry.is_opaque(context.get_block(&above)) {
+            context.set_block(position, self.dirt);
+            return;
+        }
+        if light_at(context, &above) < self.min_light {
+            return;
+        }
+
+        let random = context.random();
+        let target = position.offset(
+            random.next_below(3) as i64 - 1,
+            random.next_below(5) as i64 - 3,
+            random.next_below(3) as i64 - 1
+        );
+        let target_above = target.offset(0, 1, 0);
+        if context.get_block(&target) == self.dirt
+            && !registry.is_opaque(context.get_block(&target_above))
+            && light_at(context, &target_above) >= self.min_light {
+            context.set_block(&target, block);
+        }
+    }
+}
+
+// Crops move on to their next stage on some of their random ticks and break when the soil below them goes
+pub struct CropGrowth {
+    pub stages: Vec<u16>,
+    pub soil: Vec<u16>,
+    pub min_light: u8,
+    // One in this many random ticks grows the crop
+    pub growth_chance: u64
+}
+
+impl TickHandler for CropGrowth {
+    fn random_tick(&self, context: &mut TickContext, position: &Position, block: u16) {
+        let Some(stage) = self.stages.iter().position(|stage| *stage == block) else {
+            return;
+        };
+        if stage + 1 == self.stages.len() || light_at(context, position) < self.min_light {
+            return;
+        }
+
+        if context.random().next_below(self.growth_chance) == 0 {
+            context.set_block(position, self.stages[stage + 1]);
+        }
+    }
+
+    fn neighbour_changed(&self, context: &mut TickContext, position: &Position, _block: u16) {
+        let below = position.offset(0, -1, 0);
+        if context.is_loaded(&below) && !self.soil.contains(&context.get_block(&below)) {
+            context.set_block(position, BLOCK_TYPE_AIR);
+        }
+    }
+}
+
+// Hooks up the behaviours of the blocks in blocks.ron, blocks the registry does not know are left out
+pub fn register_defaults(scheduler: &mut TickScheduler, registry: &BlockRegistry) {
+    let ids = |names: &[&str]| names.iter().filter_map(|name| registry.id(name)).collect::<Vec<_>>();
+
+    scheduler.register(ids(&["sand"]), Gravity { fall_delay: 2 });
+
+    if let Some(dirt) = registry.id("dirt") {
+        scheduler.register(ids(&["grass"]), GrassSpread { dirt, min_light: 9 });
+    }
+
+    let wheat = ids(&["wheat_0", "wheat_1", "wheat_2", "wheat_3"]);
+    scheduler.register(wheat.clone(), CropGrowth {
+        stages: wheat,
+        soil: ids(&["dirt", "grass"]),
+        min_light: 9,
+        growth_chance: 4
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tick::test_support::{floor_world, run, run_until};
+    use crate::tick::TickConfig;
+    use crate::world::World;
+
+    // A dirt floor at y = -1 under open sky
+    fn setup() -> (World, BlockRegistry, TickScheduler) {
+        let registry = BlockRegistry::load("blocks.ron");
+        let world = floor_world(&registry, registry.id("dirt").unwrap());
+
+        // More random ticks than in game so slow changes finish within a few thousand ticks
+        let mut scheduler = TickScheduler::new(5, TickConfig { random_ticks_per_chunk: 64, ..TickConfig::default() });
+        register_defaults(&mut scheduler, &registry);
+        (world, registry, scheduler)
+    }
+
+    #[test]
+    fn sand_falls_until_it_lands() {
+        let (mut world, registry, mut scheduler) = setup();
+        let sand = registry.id("sand").unwrap();
+        let stone = registry.id("stone").unwrap();
+
+        scheduler.set_block(&mut world, &registry, &Position::new(3, 6, 3), stone);
+        world.set_block(&Position::new(3, 7, 3), sand);
+        world.set_block(&Position::new(3, 8, 3), sand);
+        scheduler.set_block(&mut world, &registry, &Position::new(3, 6, 3), BLOCK_TYPE_AIR);
+        run(&mut world, &registry, &mut scheduler, 40);
+
+        assert!(scheduler.is_idle());
+        assert_eq!(world.get_block(&Position::new(3, 0, 3)), sand);
+        assert_eq!(world.get_block(&Position::new(3, 1, 3)), sand);
+        assert!((2..9).all(|y| world.get_block(&Position::new(3, y, 3)) == BLOCK_TYPE_AIR));
+    }
+
+    #[test]
+    fn grass_spreads_over_lit_dirt_and_dies_in_the_dark() {
+        let (mut world, registry, mut scheduler) = setup();
+        let grass = registry.id("grass").unwrap();
+        let dirt = registry.id("dirt").unwrap();
+        let stone = registry.id("stone").unwrap();
+
+        scheduler.set_block(&mut world, &registry, &Position::new(0, -1, 0), grass);
+        run(&mut world, &registry, &mut scheduler, 2000);
+
+        let grass_count = (-8..8).flat_map(|x| (-8..8).map(move |z| (x, z)))
+            .filter(|(x, z)| world.get_block(&Position::new(*x, -1, *z)) == grass)
+            .count();
+        assert!(grass_count > 20, "Grass only spread to {} blocks", grass_count);
+        assert_eq!(world.get_block(&Position::new(0, -2, 0)), dirt);
+
+        // Covered grass turns back into dirt
+        scheduler.set_block(&mut world, &registry, &Position::new(0, 0, 0), stone);
+        assert!(run_until(&mut world, &registry, &mut scheduler, 2000, |world| world.get_block(&Position::new(0, -1, 0)) != grass));
+        assert_eq!(world.get_block(&Position::new(0, -1, 0)), dirt);
+    }
+
+    #[test]
+    fn crops_grow_through_their_stages_and_need_soil() {
+        let (mut world, registry, mut scheduler) = setup();
+        let stages = ["wheat_0", "wheat_1", "wheat_2", "wheat_3"].map(|name| registry.id(name).unwrap());
+        let stone = registry.id("stone").unwrap();
+
+        scheduler.set_block(&mut world, &registry, &Position::new(2, 0, 2), stages[0]);
+        let mut seen = vec![stages[0]];
+        let grown = run_until(&mut world, &registry, &mut scheduler, 5000, |world| {
+            let block = world.get_block(&Position::new(2, 0, 2));
+            if *seen.last().unwrap() != block {
+                seen.push(block);
+            }
+            block == stages[3]
+        });
+        assert!(grown);
+        assert_eq!(seen, stages);
+
+        // Crops do not take on stone and break when their soil is gone
+        scheduler.set_block(&mut world, &registry, &Position::new(5, -1, 5), stone);
+        scheduler.set_block(&mut world, &registry, &Position::new(5, 0, 5), stages[0]);
+        assert_eq!(world.get_block(&Position::new(5, 0, 5)), BLOCK_TYPE_AIR);
+        scheduler.set_block(&mut world, &registry, &Position::new(2, -1, 2), stone);
+        assert_eq!(world.get_block(&Position::new(2, 0, 2)), BLOCK_TYPE_AIR);
+    }
+}
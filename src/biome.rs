@@ -0,0 +1,215 @@
+use std::fs;
+use noise::{Fbm, MultiFractal, NoiseFn, Perlin};
+use ron::extensions::Extensions;
+use serde::Deserialize;
+use crate::block::BlockRegistry;
+use crate::world::Position;
+
+#[derive(Debug, Deserialize)]
+pub struct BiomeDefinition {
+    pub name: String,
+    pub temperature: f64,
+    pub humidity: f64,
+    pub surface_block: String,
+    pub filler_block: String,
+    pub filler_depth: i64,
+    pub base_height: f64,
+    pub amplitude: f64,
+    #[serde(default)]
+    pub features: Vec<String>
+}
+
+#[derive(Debug, Clone)]
+pub struct Biome {
+    pub name: String,
+    pub temperature: f64,
+    pub humidity: f64,
+    pub surface_block: u16,
+    pub filler_block: u16,
+    pub filler_depth: i64,
+    pub base_height: f64,
+    pub amplitude: f64,
+    pub features: Vec<String>
+}
+
+#[derive(Debug, Clone)]
+pub struct BiomeConfig {
+    pub climate_frequency: f64,
+    // Higher values make the transition between neighbouring biomes narrower
+    pub blend_sharpness: f64
+}
+
+impl Default for BiomeConfig {
+    fn default() -> Self {
+        Self {
+            climate_frequency: 1.0 / 512.0,
+            blend_sharpness: 24.0
+        }
+    }
+}
+
+// Picks biomes from temperature and humidity noise sampled in world space
+pub struct BiomeMap {
+    biomes: Vec<Biome>,
+    config: BiomeConfig,
+    temperature_noise: Fbm<Perlin>,
+    humidity_noise: Fbm<Perlin>
+}
+
+impl BiomeMap {
+    pub fn load(path: &str, registry: &BlockRegistry, seed: u32, config: BiomeConfig) -> Self {
+        let source = fs::read_to_string(path).unwrap_or_else(|_| panic!("Failed to load {}", path));
+        Self::from_ron(&source, registry, seed, config).unwrap_or_else(|error| panic!("Failed to parse {}: {}", path, error))
+    }
+
+    pub fn from_ron(source: &str, registry: &BlockRegistry, seed: u32, config: BiomeConfig) -> Result<Self, String> {
+        let definitions: Vec<BiomeDefinition> = ron::Options::default()
+            .with_default_extension(Extensions::IMPLICIT_SOME)
+            .from_str(source)
+            .map_err(|error| error.to_string())?;
+
+        if definitions.is_empty() {
+            return Err("At least one biome has to be defined".to_owned());
+        }
+
+        let block = |name: &str| registry.id(name).ok_or_else(|| format!("Unknown block {}", name));
+        let biomes = definitions.into_iter()
+            .map(|definition| Ok(Biome {
+                surface_block: block(&definition.surface_block)?,
+                filler_block: block(&definition.filler_block)?,
+                name: definition.name,
+                temperature: definition.temperature,
+                humidity: definition.humidity,
+                filler_depth: definition.filler_depth,
+                base_height: definition.base_height,
+                amplitude: definition.amplitude,
+                features: definition.features
+            }))
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let climate_noise = |seed: u32| Fbm::<Perlin>::new(seed)
+            .set_octaves(3)
+            .set_frequency(config.climate_frequency);
+
+        Ok(Self {
+            biomes,
+            temperature_noise: climate_noise(seed.wrapping_add(10)),
+            humidity_noise: climate_noise(seed.wrapping_add(11)),
+            config
+        })
+    }
+
+    pub fn biomes(&self) -> &[Biome] {
+        &self.biomes
+    }
+
+    pub fn climate(&self, x: i64, z: i64) -> (f64, f64) {
+        let point = [x as f64, z as f64];
+        (self.temperature_noise.get(point), self.humidity_noise.get(point))
+    }
+
+    pub fn biome_at(&self, position: &Position) -> &Biome {
+        self.biome_at_column(position.x, position.z)
+    }
+
+    pub fn biome_at_column(&self, x: i64, z: i64) -> &Biome {
+        let (temperature, humidity) = self.climate(x, z);
+
+        self.biomes.iter()
+            .min_by(|a, b| {
+                let distance_a = climate_distance(a, temperature, humidity);
+                let distance_b = climate_distance(b, temperature, humidity);
+                distance_a.total_cmp(&distance_b)
+            })
+            .unwrap()
+    }
+
+    // Weights change continuously with the climate, so heights blend without cliffs between biomes
+    pub fn weights(&self, x: i64, z: i64) -> Vec<f64> {
+        let (temperature, humidity) = self.climate(x, z);
+        let nearest = self.biomes.iter()
+            .map(|biome| climate_distance(biome, temperature, humidity))
+            .fold(f64::INFINITY, f64::min);
+
+        let weights = self.biomes.iter()
+            .map(|biome| (-(climate_distance(biome, temperature, humidity) - nearest) * self.config.blend_sharpness).exp())
+            .collect::<Vec<_>>();
+
+        let total = weights.iter().sum::<f64>();
+        weights.into_iter().map(|weight| weight / total).collect()
+    }
+
+    pub fn terrain_shape(&self, x: i64, z: i64) -> (f64, f64) {
+        self.weights(x, z).into_iter()
+            .zip(self.biomes.iter())
+            .fold((0.0, 0.0), |(base_height, amplitude), (weight, biome)| {
+                (base_height + biome.base_height * weight, amplitude + biome.amplitude * weight)
+            })
+    }
+}
+
+fn climate_distance(biome: &Biome, temperature: f64, humidity: f64) -> f64 {
+    (biome.temperature - temperature).powi(2) + (biome.humidity - humidity).powi(2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::worldgen::{HeightmapGenerator, TerrainBlocks, TerrainConfig};
+
+    fn generator(seed: u32) -> HeightmapGenerator {
+        let registry = BlockRegistry::load("blocks.ron");
+        let biomes = BiomeMap::load("biomes.ron", &registry, seed, BiomeConfig::default());
+        HeightmapGenerator::new(seed, TerrainConfig::default(), TerrainBlocks::from_registry(&registry))
+            .with_biomes(biomes)
+    }
+
+    #[test]
+    fn weights_are_normalised() {
+        let registry = BlockRegistry::load("blocks.ron");
+        let biomes = BiomeMap::load("biomes.ron", &registry, 3, BiomeConfig::default());
+
+        for x in (-2000..2000).step_by(97) {
+            let weights = biomes.weights(x, -x / 2);
+            assert!((weights.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+
+            let nearest = biomes.biome_at(&Position::new(x, 0, -x / 2));
+            let heaviest = weights.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1)).unwrap().0;
+            assert_eq!(biomes.biomes()[heaviest].name, nearest.name);
+        }
+    }
+
+    #[test]
+    fn covers_several_biomes() {
+        let generator = generator(11);
+        let mut names = Vec::new();
+
+        for x in (-8000..8000).step_by(128) {
+            for z in (-8000..8000).step_by(128) {
+                let name = generator.biome_at(&Position::new(x, 0, z)).unwrap().name.clone();
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+
+        assert!(names.len() >= 3, "{:?}", names);
+    }
+
+    #[test]
+    fn heights_blend_between_biomes() {
+        let generator = generator(11);
+        let mut max_step = 0;
+
+        for z in [-3000, 0, 1700] {
+            let mut previous = generator.surface_height(-4000, z);
+            for x in -3999..4000 {
+                let height = generator.surface_height(x, z);
+                max_step = max_step.max((height - previous).abs());
+                previous = height;
+            }
+        }
+
+        assert!(max_step <= 4, "steepest step was {}", max_step);
+    }
+}
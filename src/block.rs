@@ -0,0 +1,347 @@
+use std::collections::HashMap;
+use std::fs;
+use ron::extensions::Extensions;
+use serde::Deserialize;
+use crate::light::MAX_LIGHT;
+use crate::world::{Position, BLOCK_TYPE_AIR};
+
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum Face {
+    Bottom,
+    Top,
+    West,
+    East,
+    North,
+    South
+}
+
+impl Face {
+    pub const ALL: [Face; 6] = [Face::Bottom, Face::Top, Face::West, Face::East, Face::North, Face::South];
+
+    pub fn normal(&self) -> Position {
+        match self {
+            Face::Bottom => Position::new(0, -1, 0),
+            Face::Top => Position::new(0, 1, 0),
+            Face::West => Position::new(-1, 0, 0),
+            Face::East => Position::new(1, 0, 0),
+            Face::North => Position::new(0, 0, 1),
+            Face::South => Position::new(0, 0, -1)
+        }
+    }
+}
+
+// Which pass draws the faces of a block
+#[derive(Copy, Clone, Debug, Default, Hash, PartialEq, Eq, Deserialize)]
+pub enum RenderLayer {
+    #[default]
+    Opaque,
+    // Fully transparent texels are cut away, the rest is drawn like opaque blocks
+    Cutout,
+    // Blended over everything behind it, drawn last and back to front
+    Translucent
+}
+
+impl RenderLayer {
+    pub const ALL: [RenderLayer; 3] = [RenderLayer::Opaque, RenderLayer::Cutout, RenderLayer::Translucent];
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct BlockTextures {
+    pub all: Option<String>,
+    pub side: Option<String>,
+    pub top: Option<String>,
+    pub bottom: Option<String>,
+    pub west: Option<String>,
+    pub east: Option<String>,
+    pub north: Option<String>,
+    pub south: Option<String>
+}
+
+impl BlockTextures {
+    pub fn get(&self, face: Face) -> Option<&str> {
+        let specific = match face {
+            Face::Bottom => &self.bottom,
+            Face::Top => &self.top,
+            Face::West => &self.west,
+            Face::East => &self.east,
+            Face::North => &self.north,
+            Face::South => &self.south
+        };
+
+        let side = match face {
+            Face::Bottom | Face::Top => &None,
+            _ => &self.side
+        };
+
+        specific.as_ref()
+            .or(side.as_ref())
+            .or(self.all.as_ref())
+            .map(|name| name.as_str())
+    }
+}
+
+// Makes a block the source of a fluid. Flowing blocks are added for every level, with the ids from
+// first_flowing_id on and the name of the source followed by the level, like water_3.
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct FluidDefinition {
+    pub first_flowing_id: u16,
+    pub levels: u8
+}
+
+// Level 0 is the source, every step away from it adds one up to the fluid's number of levels
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FluidState {
+    pub source: u16,
+    pub level: u8,
+    pub levels: u8
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BlockDefinition {
+    pub name: String,
+    pub id: u16,
+    pub opaque: bool,
+    pub solid: bool,
+    #[serde(default)]
+    pub light_emission: u8,
+    #[serde(default)]
+    pub render_layer: RenderLayer,
+    #[serde(default)]
+    pub fluid: Option<FluidDefinition>,
+    #[serde(default)]
+    pub textures: BlockTextures
+}
+
+#[derive(Debug, Default)]
+pub struct BlockRegistry {
+    blocks: Vec<Option<BlockDefinition>>,
+    ids: HashMap<String, u16>,
+    fluids: HashMap<u16, FluidState>
+}
+
+impl BlockRegistry {
+    pub fn load(path: &str) -> Self {
+        let source = fs::read_to_string(path).unwrap_or_else(|_| panic!("Failed to load {}", path));
+        Self::from_ron(&source).unwrap_or_else(|error| panic!("Failed to parse {}: {}", path, error))
+    }
+
+    pub fn from_ron(source: &str) -> Result<Self, String> {
+        let definitions: Vec<BlockDefinition> = ron::Options::default()
+            .with_default_extension(Extensions::IMPLICIT_SOME)
+            .from_str(source)
+            .map_err(|error| error.to_string())?;
+        Self::from_definitions(definitions)
+    }
+
+    pub fn from_definitions(mut definitions: Vec<BlockDefinition>) -> Result<Self, String> {
+        let mut registry = Self::default();
+
+        let mut flowing = Vec::new();
+        for definition in definitions.iter() {
+            let Some(fluid) = definition.fluid else {
+                continue;
+            };
+            if fluid.levels == 0 {
+                return Err(format!("Fluid {} needs at least one flowing level", definition.name));
+            }
+
+            registry.fluids.insert(definition.id, FluidState { source: definition.id, level: 0, levels: fluid.levels });
+            for level in 1..=fluid.levels {
+                let id = fluid.first_flowing_id.checked_add(level as u16 - 1)
+                    .ok_or_else(|| format!("Flowing ids of {} run past {}", definition.name, u16::MAX))?;
+                registry.fluids.insert(id, FluidState { source: definition.id, level, levels: fluid.levels });
+                flowing.push(BlockDefinition {
+                    name: format!("{}_{}", definition.name, level),
+                    id,
+                    opaque: definition.opaque,
+                    solid: definition.solid,
+                    light_emission: definition.light_emission,
+                    render_layer: definition.render_layer,
+                    fluid: None,
+                    textures: definition.textures.clone()
+                });
+            }
+        }
+        definitions.extend(flowing);
+
+        for definition in definitions {
+            if registry.ids.contains_key(&definition.name) {
+                return Err(format!("Block name {} is defined twice", definition.name));
+            }
+
+            let index = definition.id as usize;
+            if registry.blocks.len() <= index {
+                registry.blocks.resize_with(index + 1, || None);
+            }
+
+            if definition.light_emission > MAX_LIGHT {
+                return Err(format!("Block {} emits light {}, the maximum is {}", definition.name, definition.light_emission, MAX_LIGHT));
+            }
+
+            if definition.opaque && definition.render_layer != RenderLayer::Opaque {
+                return Err(format!("Block {} is opaque but drawn in the {:?} layer", definition.name, definition.render_layer));
+            }
+
+            if let Some(existing) = &registry.blocks[index] {
+                return Err(format!("Blocks {} and {} share id {}", existing.name, definition.name, definition.id));
+            }
+
+            registry.ids.insert(definition.name.clone(), definition.id);
+            registry.blocks[index] = Some(definition);
+        }
+
+        match registry.get(BLOCK_TYPE_AIR) {
+            Some(air) if !air.opaque && !air.solid => Ok(registry),
+            _ => Err(format!("Block id {} must be defined as a non-opaque, non-solid air block", BLOCK_TYPE_AIR))
+        }
+    }
+
+    pub fn get(&self, id: u16) -> Option<&BlockDefinition> {
+        self.blocks.get(id as usize).and_then(|block| block.as_ref())
+    }
+
+    pub fn id(&self, name: &str) -> Option<u16> {
+        self.ids.get(name).copied()
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<&BlockDefinition> {
+        self.id(name).and_then(|id| self.get(id))
+    }
+
+    pub fn is_opaque(&self, id: u16) -> bool {
+        self.get(id).is_some_and(|block| block.opaque)
+    }
+
+    pub fn is_solid(&self, id: u16) -> bool {
+        self.get(id).is_some_and(|block| block.solid)
+    }
+
+    pub fn light_emission(&self, id: u16) -> u8 {
+        self.get(id).map_or(0, |block| block.light_emission)
+    }
+
+    pub fn fluid(&self, id: u16) -> Option<FluidState> {
+        self.fluids.get(&id).copied()
+    }
+
+    // Block of the fluid with the given source at a level, the source itself at level 0
+    pub fn fluid_block(&self, source: u16, level: u8) -> Option<u16> {
+        let fluid = self.get(source)?.fluid?;
+        match level {
+            0 => Some(source),
+            level if level <= fluid.levels => Some(fluid.first_flowing_id + level as u16 - 1),
+            _ => None
+        }
+    }
+
+    pub fn is_same_fluid(&self, a: u16, b: u16) -> bool {
+        match (self.fluid(a), self.fluid(b)) {
+            (Some(a), Some(b)) => a.source == b.source,
+            _ => false
+        }
+    }
+
+    pub fn render_layer(&self, id: u16) -> RenderLayer {
+        self.get(id).map_or(RenderLayer::Opaque, |block| block.render_layer)
+    }
+
+    // A face is drawn unless the block it faces hides it completely. Translucent blocks also hide the faces
+    // between blocks of their own type, a lake has no inner surfaces. The same goes for all levels of a fluid,
+    // the mesher draws the steps between different levels itself.
+    pub fn is_face_visible(&self, block: u16, neighbour: u16) -> bool {
+        let merged = block == neighbour && self.render_layer(block) == RenderLayer::Translucent || self.is_same_fluid(block, neighbour);
+        block != BLOCK_TYPE_AIR && !self.is_opaque(neighbour) && !merged
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &BlockDefinition> {
+        self.blocks.iter().flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_shipped_blocks() {
+        let registry = BlockRegistry::load("blocks.ron");
+        let stone = registry.by_name("stone").unwrap();
+
+        assert!(stone.opaque);
+        assert_eq!(registry.id("air"), Some(BLOCK_TYPE_AIR));
+        assert!(registry.is_face_visible(stone.id, BLOCK_TYPE_AIR));
+        assert!(!registry.is_face_visible(stone.id, stone.id));
+    }
+
+    #[test]
+    fn translucent_blocks_hide_their_own_faces() {
+        let registry = BlockRegistry::load("blocks.ron");
+        let water = registry.id("water").unwrap();
+        let leaves = registry.id("leaves").unwrap();
+        let stone = registry.id("stone").unwrap();
+
+        assert_eq!(registry.render_layer(water), RenderLayer::Translucent);
+        assert!(!registry.is_face_visible(water, water));
+        assert!(registry.is_face_visible(water, leaves));
+        assert!(registry.is_face_visible(stone, water));
+
+        // Cutouts can be seen through, so their inner faces stay
+        assert_eq!(registry.render_layer(leaves), RenderLayer::Cutout);
+        assert!(registry.is_face_visible(leaves, leaves));
+    }
+
+    #[test]
+    fn resolves_face_textures() {
+        let registry = BlockRegistry::from_ron(r#"[
+            (name: "air", id: 0, opaque: false, solid: false),
+            (name: "log", id: 1, opaque: true, solid: true, textures: (all: "log_top", side: "log_side", bottom: "log_bottom"))
+        ]"#).unwrap();
+        let textures = &registry.by_name("log").unwrap().textures;
+
+        assert_eq!(textures.get(Face::Top), Some("log_top"));
+        assert_eq!(textures.get(Face::Bottom), Some("log_bottom"));
+        assert_eq!(textures.get(Face::North), Some("log_side"));
+    }
+
+    #[test]
+    fn fluids_get_a_block_for_every_level() {
+        let registry = BlockRegistry::from_ron(r#"[
+            (name: "air", id: 0, opaque: false, solid: false),
+            (name: "water", id: 1, opaque: false, solid: false, fluid: (first_flowing_id: 10, levels: 3), textures: (all: "water"))
+        ]"#).unwrap();
+        let water = registry.id("water").unwrap();
+
+        assert_eq!(registry.id("water_3"), Some(12));
+        assert_eq!(registry.by_name("water_2").unwrap().textures.get(Face::Top), Some("water"));
+        assert_eq!(registry.fluid(11), Some(FluidState { source: water, level: 2, levels: 3 }));
+        assert_eq!(registry.fluid_block(water, 0), Some(water));
+        assert_eq!(registry.fluid_block(water, 3), Some(12));
+        assert_eq!(registry.fluid_block(water, 4), None);
+        assert!(!registry.is_face_visible(water, 12));
+
+        // Flowing ids must not collide with other blocks
+        assert!(BlockRegistry::from_ron(r#"[
+            (name: "air", id: 0, opaque: false, solid: false),
+            (name: "water", id: 1, opaque: false, solid: false, fluid: (first_flowing_id: 2, levels: 3)),
+            (name: "stone", id: 3, opaque: true, solid: true)
+        ]"#).is_err());
+    }
+
+    #[test]
+    fn rejects_conflicting_definitions() {
+        assert!(BlockRegistry::from_ron(r#"[
+            (name: "air", id: 0, opaque: false, solid: false),
+            (name: "stone", id: 0, opaque: true, solid: true)
+        ]"#).is_err());
+
+        assert!(BlockRegistry::from_ron(r#"[
+            (name: "stone", id: 1, opaque: true, solid: true)
+        ]"#).is_err());
+
+        assert!(BlockRegistry::from_ron(r#"[
+            (name: "air", id: 0, opaque: false, solid: false),
+            (name: "glass", id: 1, opaque: true, solid: true, render_layer: Translucent)
+        ]"#).is_err());
+    }
+}
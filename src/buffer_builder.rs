@@ -1,8 +1,10 @@
 use std::{mem, slice};
-use wgpu::{Buffer, BufferUsages, Device};
+use glam::Vec3;
+use wgpu::{Buffer, BufferUsages, Device, Queue};
 use wgpu::util::{BufferInitDescriptor, DeviceExt};
 use crate::util::VSInput;
 
+#[derive(Default)]
 pub struct BufferBuilder {
     vertices: Vec<VSInput>,
     indices: Vec<u32>
@@ -16,6 +18,18 @@ impl BufferBuilder {
         }
     }
 
+    pub fn vertices(&self) -> &[VSInput] {
+        &self.vertices
+    }
+
+    pub fn indices(&self) -> &[u32] {
+        &self.indices
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
     pub fn add_quad(&mut self, tl: VSInput, tr: VSInput, br: VSInput, bl: VSInput) -> &mut Self {
         let offset = self.vertices.len() as u32;
 
@@ -24,17 +38,36 @@ impl BufferBuilder {
         self.vertices.push(br);
         self.vertices.push(bl);
 
-        self.indices.push(offset + 0);
+        self.indices.push(offset);
         self.indices.push(offset + 1);
         self.indices.push(offset + 2);
 
         self.indices.push(offset + 2);
         self.indices.push(offset + 3);
-        self.indices.push(offset + 0);
+        self.indices.push(offset);
 
         self
     }
 
+    // Reorders the quads so the farthest from the eye comes first, for blending back to front
+    pub fn sort_quads(&mut self, eye: Vec3) {
+        let mut quads = self.indices.chunks(6)
+            .map(|indices| {
+                // Every quad owns the four vertices starting at its first index
+                let first = indices[0] as usize;
+                let center = self.vertices[first..first + 4].iter().fold(Vec3::ZERO, |sum, vertex| sum + vertex.position()) / 4.0;
+                (center.distance_squared(eye), indices)
+            })
+            .collect::<Vec<_>>();
+        quads.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        self.indices = quads.into_iter().flat_map(|(_, indices)| indices.iter().copied()).collect();
+    }
+
+    // Uploads the indices again after sorting, the buffer has to come from build
+    pub fn write_indices(&self, queue: &Queue, index_buffer: &Buffer) {
+        queue.write_buffer(index_buffer, 0, unsafe { slice::from_raw_parts(self.indices.as_ptr().cast(), self.indices.len() * mem::size_of::<u32>())});
+    }
+
     pub fn build(&mut self, device: &Device) -> (Buffer, Buffer, u32) {
         let v = device.create_buffer_init(&BufferInitDescriptor {
             label: None,
@@ -45,9 +78,33 @@ impl BufferBuilder {
         let i = device.create_buffer_init(&BufferInitDescriptor {
             label: None,
             contents: unsafe { slice::from_raw_parts(self.indices.as_ptr().cast(), self.indices.len() * mem::size_of::<u32>())},
-            usage: BufferUsages::INDEX,
+            usage: BufferUsages::INDEX | BufferUsages::COPY_DST,
         });
 
         (v, i, self.indices.len() as _)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec2;
+    use super::*;
+
+    fn quad_at(builder: &mut BufferBuilder, z: f32) {
+        let vertex = |x: f32, y: f32| VSInput::new(Vec3::new(x, y, z), Vec2::ZERO);
+        builder.add_quad(vertex(0.0, 1.0), vertex(1.0, 1.0), vertex(1.0, 0.0), vertex(0.0, 0.0));
+    }
+
+    #[test]
+    fn sorts_quads_back_to_front() {
+        let mut builder = BufferBuilder::new();
+        for z in [2.0, 8.0, -3.0, 5.0] {
+            quad_at(&mut builder, z);
+        }
+
+        builder.sort_quads(Vec3::new(0.5, 0.5, 0.0));
+        let order = builder.indices().chunks(6).map(|quad| builder.vertices()[quad[0] as usize].position().z).collect::<Vec<_>>();
+        assert_eq!(order, [8.0, 5.0, -3.0, 2.0]);
+        assert_eq!(&builder.indices()[..6], &[4, 5, 6, 6, 7, 4]);
+    }
+}
@@ -0,0 +1,148 @@
+use noise::{Fbm, MultiFractal, NoiseFn, Perlin};
+use crate::coords;
+use crate::world::{Chunk, Position, BLOCK_TYPE_AIR, CHUNK_SIZE_X, CHUNK_SIZE_Y, CHUNK_SIZE_Z};
+
+#[derive(Debug, Clone)]
+pub struct CaveConfig {
+    // Large open caverns where fractal noise rises above the threshold
+    pub cheese_frequency: f64,
+    pub cheese_threshold: f64,
+    // Tunnels where two noise fields are both close to zero
+    pub worm_frequency: f64,
+    pub worm_radius: f64,
+    pub min_y: i64,
+    pub max_y: i64
+}
+
+impl Default for CaveConfig {
+    fn default() -> Self {
+        Self {
+            cheese_frequency: 1.0 / 48.0,
+            cheese_threshold: 0.55,
+            worm_frequency: 1.0 / 64.0,
+            worm_radius: 0.06,
+            min_y: -128,
+            max_y: 24
+        }
+    }
+}
+
+pub struct CaveCarver {
+    config: CaveConfig,
+    preserved: Vec<u16>,
+    cheese_noise: Fbm<Perlin>,
+    worm_noise: [Perlin; 2]
+}
+
+impl CaveCarver {
+    pub fn new(seed: u32, config: CaveConfig, preserved: Vec<u16>) -> Self {
+        let cheese_noise = Fbm::<Perlin>::new(seed.wrapping_add(1))
+            .set_octaves(3)
+            .set_frequency(config.cheese_frequency);
+
+        Self {
+            config,
+            preserved,
+            cheese_noise,
+            worm_noise: [Perlin::new(seed.wrapping_add(2)), Perlin::new(seed.wrapping_add(3))]
+        }
+    }
+
+    // Only depends on the world position so tunnels continue seamlessly into neighbouring chunks
+    pub fn is_cave(&self, position: &Position) -> bool {
+        if position.y < self.config.min_y || position.y > self.config.max_y {
+            return false;
+        }
+
+        let x = position.x as f64;
+        let y = position.y as f64;
+        let z = position.z as f64;
+
+        if self.cheese_noise.get([x, y * 1.5, z]) > self.config.cheese_threshold {
+            return true;
+        }
+
+        // Squash the vertical axis so worm tunnels wander mostly sideways
+        let frequency = self.config.worm_frequency;
+        let point = [x * frequency, y * frequency * 2.0, z * frequency];
+        self.worm_noise.iter().all(|noise| noise.get(point).abs() < self.config.worm_radius)
+    }
+
+    pub fn carves(&self, block: u16, position: &Position) -> bool {
+        block != BLOCK_TYPE_AIR && !self.preserved.contains(&block) && self.is_cave(position)
+    }
+
+    pub fn carve(&self, chunk: &mut Chunk) {
+        let origin = coords::chunk_origin(chunk.position());
+        if origin.y + CHUNK_SIZE_Y <= self.config.min_y || origin.y > self.config.max_y {
+            return;
+        }
+
+        for x in 0..CHUNK_SIZE_X {
+            for y in 0..CHUNK_SIZE_Y {
+                for z in 0..CHUNK_SIZE_Z {
+                    let local_position = Position::new(x, y, z);
+                    let block = chunk.get_block(&local_position);
+
+                    if self.carves(block, &coords::local_to_world(chunk.position(), &local_position)) {
+                        chunk.set_block(BLOCK_TYPE_AIR, &local_position);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn carving_only_depends_on_world_position() {
+        let stone = 1;
+        let carver = CaveCarver::new(99, CaveConfig::default(), Vec::new());
+
+        let mut carved_blocks = 0;
+        for chunk_x in -1..=0 {
+            for chunk_y in -2..=-1 {
+                let mut chunk = Chunk::new(Position::new(chunk_x, chunk_y, 0), stone);
+                carver.carve(&mut chunk);
+
+                for x in 0..CHUNK_SIZE_X {
+                    for y in 0..CHUNK_SIZE_Y {
+                        for z in 0..CHUNK_SIZE_Z {
+                            let local_position = Position::new(x, y, z);
+                            let world_position = coords::local_to_world(chunk.position(), &local_position);
+                            let carved = chunk.get_block(&local_position) == BLOCK_TYPE_AIR;
+
+                            assert_eq!(carved, carver.is_cave(&world_position), "{:?}", world_position);
+                            carved_blocks += carved as usize;
+                        }
+                    }
+                }
+            }
+        }
+
+        assert!(carved_blocks > 0);
+    }
+
+    #[test]
+    fn respects_depth_range_and_preserved_blocks() {
+        let config = CaveConfig {
+            cheese_threshold: -1.0,
+            min_y: 0,
+            max_y: 7,
+            ..CaveConfig::default()
+        };
+        let carver = CaveCarver::new(5, config, vec![2]);
+
+        let mut chunk = Chunk::new(Position::default(), 1);
+        chunk.set_block(2, &Position::new(4, 4, 4));
+        carver.carve(&mut chunk);
+
+        assert_eq!(chunk.get_block(&Position::new(0, 0, 0)), BLOCK_TYPE_AIR);
+        assert_eq!(chunk.get_block(&Position::new(0, 7, 0)), BLOCK_TYPE_AIR);
+        assert_eq!(chunk.get_block(&Position::new(0, 8, 0)), 1);
+        assert_eq!(chunk.get_block(&Position::new(4, 4, 4)), 2);
+    }
+}
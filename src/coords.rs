@@ -0,0 +1,142 @@
+use crate::world::{Position, CHUNK_SIZE_X, CHUNK_SIZE_Y, CHUNK_SIZE_Z};
+
+// World positions are split into the position of the owning chunk and the position inside that chunk.
+// Division has to round towards negative infinity, otherwise everything west, south or below the origin
+// ends up in the wrong chunk with a negative local index.
+
+pub fn world_to_chunk(position: &Position) -> Position {
+    Position::new(position.x.div_euclid(CHUNK_SIZE_X),
+                  position.y.div_euclid(CHUNK_SIZE_Y),
+                  position.z.div_euclid(CHUNK_SIZE_Z))
+}
+
+pub fn world_to_local(position: &Position) -> Position {
+    Position::new(position.x.rem_euclid(CHUNK_SIZE_X),
+                  position.y.rem_euclid(CHUNK_SIZE_Y),
+                  position.z.rem_euclid(CHUNK_SIZE_Z))
+}
+
+pub fn split(position: &Position) -> (Position, Position) {
+    (world_to_chunk(position), world_to_local(position))
+}
+
+pub fn chunk_origin(chunk_position: &Position) -> Position {
+    Position::new(chunk_position.x * CHUNK_SIZE_X,
+                  chunk_position.y * CHUNK_SIZE_Y,
+                  chunk_position.z * CHUNK_SIZE_Z)
+}
+
+pub fn local_to_world(chunk_position: &Position, local_position: &Position) -> Position {
+    let origin = chunk_origin(chunk_position);
+    Position::new(origin.x + local_position.x, origin.y + local_position.y, origin.z + local_position.z)
+}
+
+pub fn is_local(position: &Position) -> bool {
+    (0..CHUNK_SIZE_X).contains(&position.x)
+        && (0..CHUNK_SIZE_Y).contains(&position.y)
+        && (0..CHUNK_SIZE_Z).contains(&position.z)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::{Chunk, World, BLOCK_TYPE_AIR};
+
+    const BLOCK_TYPE_STONE: u16 = 1;
+
+    #[test]
+    fn splits_around_the_origin() {
+        let cases = [
+            (-33, -3, 15),
+            (-32, -2, 0),
+            (-17, -2, 15),
+            (-16, -1, 0),
+            (-15, -1, 1),
+            (-1, -1, 15),
+            (0, 0, 0),
+            (1, 0, 1),
+            (15, 0, 15),
+            (16, 1, 0),
+            (17, 1, 1),
+            (31, 1, 15),
+            (32, 2, 0)
+        ];
+
+        for (world, chunk, local) in cases {
+            for axis in 0..3 {
+                let mut position = [0; 3];
+                position[axis] = world;
+                let (chunk_position, local_position) = split(&Position::new(position[0], position[1], position[2]));
+
+                let chunk_position = [chunk_position.x, chunk_position.y, chunk_position.z];
+                let local_position = [local_position.x, local_position.y, local_position.z];
+                assert_eq!(chunk_position[axis], chunk, "chunk of {} on axis {}", world, axis);
+                assert_eq!(local_position[axis], local, "local of {} on axis {}", world, axis);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips_every_position_near_the_origin() {
+        for x in -3 * CHUNK_SIZE_X..3 * CHUNK_SIZE_X {
+            for y in -3 * CHUNK_SIZE_Y..3 * CHUNK_SIZE_Y {
+                for z in -3 * CHUNK_SIZE_Z..3 * CHUNK_SIZE_Z {
+                    let position = Position::new(x, y, z);
+                    let (chunk_position, local_position) = split(&position);
+
+                    assert!(is_local(&local_position), "{:?} -> {:?}", position, local_position);
+                    assert_eq!(local_to_world(&chunk_position, &local_position), position);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn handles_extreme_coordinates() {
+        let position = Position::new(i64::MIN, i64::MAX, i64::MIN + 1);
+        let (chunk_position, local_position) = split(&position);
+
+        assert!(is_local(&local_position));
+        assert_eq!(local_to_world(&chunk_position, &local_position), position);
+    }
+
+    #[test]
+    fn world_lookup_across_chunk_boundaries() {
+        let mut world = World::new();
+        for x in -1..=0 {
+            for y in -1..=0 {
+                for z in -1..=0 {
+                    world.add_chunk(Chunk::new(Position::new(x, y, z), BLOCK_TYPE_AIR));
+                }
+            }
+        }
+
+        let marked = [
+            Position::new(-1, -1, -1),
+            Position::new(-16, -16, -16),
+            Position::new(0, -1, 0),
+            Position::new(15, 15, 15),
+            Position::new(-1, 0, 15)
+        ];
+
+        for position in marked.iter() {
+            let (chunk_position, local_position) = split(position);
+            if let Some(chunk) = world.chunks.get_mut(&chunk_position) {
+                chunk.set_block(BLOCK_TYPE_STONE, &local_position);
+            }
+        }
+
+        for x in -CHUNK_SIZE_X..CHUNK_SIZE_X {
+            for y in -CHUNK_SIZE_Y..CHUNK_SIZE_Y {
+                for z in -CHUNK_SIZE_Z..CHUNK_SIZE_Z {
+                    let position = Position::new(x, y, z);
+                    let expected = if marked.contains(&position) { BLOCK_TYPE_STONE } else { BLOCK_TYPE_AIR };
+                    assert_eq!(world.get_block(&position), expected, "{:?}", position);
+                }
+            }
+        }
+
+        assert_eq!(world.get_block(&Position::new(-17, 0, 0)), BLOCK_TYPE_AIR);
+        assert_eq!(world.get_block(&Position::new(16, 0, 0)), BLOCK_TYPE_AIR);
+    }
+}
@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::fs;
+use ron::extensions::Extensions;
+use serde::Deserialize;
+use crate::block::{BlockRegistry, Face, FluidState};
+use crate::tick::{TickContext, TickHandler};
+use crate::world::{Position, BLOCK_TYPE_AIR};
+
+const HORIZONTAL: [Face; 4] = [Face::West, Face::East, Face::North, Face::South];
+
+// A flowing block turns into a source when enough sources surround it
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct SourceFormation {
+    pub adjacent_sources: u8,
+    // Whether the block below has to be solid or a source of the same fluid
+    pub needs_support: bool
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FluidRules {
+    pub fluid: String,
+    // Ticks between a change next to the fluid and its reaction to it
+    pub tick_delay: u64,
+    #[serde(default)]
+    pub source_formation: Option<SourceFormation>
+}
+
+// Fluids as a cellular automaton on scheduled ticks. Sources never change on their own, flowing blocks take
+// their level from their strongest neighbour and fall straight down wherever they can. Blocks only update when
+// something next to them changed, so a settled lake costs nothing.
+pub struct FluidHandler {
+    rules: HashMap<u16, FluidRules>
+}
+
+impl FluidHandler {
+    pub fn load(path: &str, registry: &BlockRegistry) -> Self {
+        let source = fs::read_to_string(path).unwrap_or_else(|_| panic!("Failed to load {}", path));
+        Self::from_ron(&source, registry).unwrap_or_else(|error| panic!("Failed to parse {}: {}", path, error))
+    }
+
+    pub fn from_ron(source: &str, registry: &BlockRegistry) -> Result<Self, String> {
+        let definitions: Vec<FluidRules> = ron::Options::default()
+            .with_default_extension(Extensions::IMPLICIT_SOME)
+            .from_str(source)
+            .map_err(|error| error.to_string())?;
+
+        let mut rules = HashMap::new();
+        for definition in definitions {
+            let id = registry.id(&definition.fluid).ok_or_else(|| format!("Unknown fluid {}", definition.fluid))?;
+            match registry.fluid(id) {
+                Some(fluid) if fluid.level == 0 => {}
+                _ => return Err(format!("Block {} is not the source of a fluid", definition.fluid))
+            }
+            if definition.tick_delay == 0 {
+                return Err(format!("Fluid {} needs a tick delay of at least 1", definition.fluid));
+            }
+            rules.insert(id, definition);
+        }
+
+        Ok(Self {
+            rules
+        })
+    }
+
+    // Every level of the fluids that have rules, to register the handler for
+    pub fn blocks(&self, registry: &BlockRegistry) -> Vec<u16> {
+        let mut blocks = Vec::new();
+        for (source, fluid) in self.rules.keys().filter_map(|source| Some((*source, registry.fluid(*source)?))) {
+            blocks.extend((0..=fluid.levels).filter_map(|level| registry.fluid_block(source, level)));
+        }
+        blocks
+    }
+
+    // Level a flowing block should have given its surroundings, None when nothing feeds it any more
+    fn expected_level(&self, context: &TickContext, position: &Position, fluid: &FluidState, formation: Option<SourceFormation>) -> Option<u8> {
+        let registry = context.registry();
+        let same_fluid = |position: &Position| registry.fluid(context.get_block(position)).filter(|other| other.source == fluid.source);
+
+        if same_fluid(&position.offset(0, 1, 0)).is_some() {
+            return Some(1);
+        }
+
+        let mut sources = 0;
+        let mut strongest = None;
+        for face in HORIZONTAL {
+            let normal = face.normal();
+            if let Some(neighbour) = same_fluid(&position.offset(normal.x, normal.y, normal.z)) {
+                sources += (neighbour.level == 0) as u8;
+                strongest = Some(strongest.map_or(neighbour.level, |level: u8| level.min(neighbour.level)));
+            }
+        }
+
+        if let Some(formation) = formation {
+            let below = position.offset(0, -1, 0);
+            let supported = registry.is_solid(context.get_block(&below)) || same_fluid(&below).is_some_and(|below| below.level == 0);
+            if sources >= formation.adjacent_sources && (supported || !formation.needs_support) {
+                return Some(0);
+            }
+        }
+
+        strongest.map(|level| level + 1).filter(|level| *level <= fluid.levels)
+    }
+}
+
+impl TickHandler for FluidHandler {
+    fn scheduled_tick(&self, context: &mut TickContext, position: &Position, block: u16) {
+        let registry = context.registry();
+        let Some(mut fluid) = registry.fluid(block) else {
+            return;
+        };
+        let Some(rules) = self.rules.get(&fluid.source) else {
+            return;
+        };
+
+        if fluid.level > 0 {
+            let expected = self.expected_level(context, position, &fluid, rules.source_formation);
+            if expected != Some(fluid.level) {
+                let block = expected.and_then(|level| registry.fluid_block(fluid.source, level)).unwrap_or(BLOCK_TYPE_AIR);
+                context.set_block(position, block);
+                match expected {
+                    Some(level) => fluid.level = level,
+                    None => return
+                }
+            }
+        }
+
+        // Falling takes priority, a fluid only spreads sideways once it lies on something
+        let below = position.offset(0, -1, 0);
+        if can_flow_into(context, &below, &fluid, 1) {
+            context.set_block(&below, registry.fluid_block(fluid.source, 1).unwrap());
+            return;
+        }
+
+        let level = fluid.level + 1;
+        if level > fluid.levels || is_falling_into(context, position, &fluid) {
+            return;
+        }
+
+        let block = registry.fluid_block(fluid.source, level).unwrap();
+        for face in HORIZONTAL {
+            let normal = face.normal();
+            let neighbour = position.offset(normal.x, normal.y, normal.z);
+            if can_flow_into(context, &neighbour, &fluid, level) {
+                context.set_block(&neighbour, block);
+            }
+        }
+    }
+
+    fn neighbour_changed(&self, context: &mut TickContext, position: &Position, block: u16) {
+        let rules = context.registry().fluid(block).and_then(|fluid| self.rules.get(&fluid.source));
+        if let Some(rules) = rules {
+            context.schedule(position, rules.tick_delay);
+        }
+    }
+}
+
+// Fluids replace air and weaker levels of themselves, but never spill into chunks that are not loaded
+fn can_flow_into(context: &TickContext, position: &Position, fluid: &FluidState, level: u8) -> bool {
+    if !context.is_loaded(position) {
+        return false;
+    }
+
+    let block = context.get_block(position);
+    block == BLOCK_TYPE_AIR || context.registry().fluid(block).is_some_and(|other| other.source == fluid.source && other.level > level)
+}
+
+fn is_falling_into(context: &TickContext, position: &Position, fluid: &FluidState) -> bool {
+    // Fluids on top of a falling column keep feeding it, only sources and solid ground let them spread
+    context.registry().fluid(context.get_block(&position.offset(0, -1, 0))).is_some_and(|below| below.source == fluid.source && below.level > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::light;
+    use crate::tick::test_support::{floor_world, run};
+    use crate::tick::{TickConfig, TickScheduler};
+    use crate::world::World;
+
+    // Open air above a stone floor at y = -1
+    fn setup() -> (World, BlockRegistry, TickScheduler) {
+        let registry = BlockRegistry::load("blocks.ron");
+        let fluids = FluidHandler::load("fluids.ron", &registry);
+        let mut scheduler = TickScheduler::new(0, TickConfig::default());
+        scheduler.register(fluids.blocks(&registry), fluids);
+        let world = floor_world(&registry, registry.id("stone").unwrap());
+        (world, registry, scheduler)
+    }
+
+    fn level(world: &World, registry: &BlockRegistry, x: i64, y: i64, z: i64) -> Option<u8> {
+        registry.fluid(world.get_block(&Position::new(x, y, z))).map(|fluid| fluid.level)
+    }
+
+    #[test]
+    fn water_spreads_out_over_a_floor() {
+        let (mut world, registry, mut scheduler) = setup();
+        let water = registry.id("water").unwrap();
+
+        scheduler.set_block(&mut world, &registry, &Position::new(0, 0, 0), water);
+        run(&mut world, &registry, &mut scheduler, 100);
+        assert!(scheduler.is_idle());
+
+        // Levels grow with the distance to the source until the water runs out
+        for x in -9..=9i64 {
+            for z in -9..=9i64 {
+                let distance = (x.abs() + z.abs()) as u8;
+                let expected = if distance <= 7 { Some(distance) } else { None };
+                assert_eq!(level(&world, &registry, x, 0, z), expected, "{} {}", x, z);
+            }
+        }
+        assert_eq!(level(&world, &registry, 0, 1, 0), None);
+        assert!(world.is_dirty(&Position::new(0, 0, 0)));
+    }
+
+    #[test]
+    fn water_falls_before_it_spreads() {
+        let (mut world, registry, mut scheduler) = setup();
+        let water = registry.id("water").unwrap();
+
+        scheduler.set_block(&mut world, &registry, &Position::new(3, 6, 3), water);
+        run(&mut world, &registry, &mut scheduler, 100);
+
+        // A falling column below the source, spreading only from where it lands
+        assert_eq!(level(&world, &registry, 3, 6, 3), Some(0));
+        assert_eq!(level(&world, &registry, 4, 6, 3), None);
+        for y in 0..6 {
+            assert_eq!(level(&world, &registry, 3, y, 3), Some(1));
+            assert_eq!(level(&world, &registry, 4, y, 3), if y == 0 { Some(2) } else { None });
+        }
+        assert_eq!(level(&world, &registry, 3, 0, 9), Some(7));
+    }
+
+    #[test]
+    fn removing_the_source_drains_the_flow() {
+        let (mut world, registry, mut scheduler) = setup();
+        let water = registry.id("water").unwrap();
+
+        scheduler.set_block(&mut world, &registry, &Position::new(0, 2, 0), water);
+        run(&mut world, &registry, &mut scheduler, 100);
+        scheduler.set_block(&mut world, &registry, &Position::new(0, 2, 0), BLOCK_TYPE_AIR);
+        run(&mut world, &registry, &mut scheduler, 200);
+
+        assert!(scheduler.is_idle());
+        for x in -9..=9 {
+            for y in 0..3 {
+                for z in -9..=9 {
+                    assert_eq!(level(&world, &registry, x, y, z), None, "{} {} {}", x, y, z);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sources_form_between_sources_when_the_rules_allow() {
+        let (mut world, registry, mut scheduler) = setup();
+        let water = registry.id("water").unwrap();
+        let lava = registry.id("lava").unwrap();
+
+        scheduler.set_block(&mut world, &registry, &Position::new(0, 0, 0), water);
+        scheduler.set_block(&mut world, &registry, &Position::new(2, 0, 0), water);
+        scheduler.set_block(&mut world, &registry, &Position::new(0, 0, 8), lava);
+        scheduler.set_block(&mut world, &registry, &Position::new(2, 0, 8), lava);
+        run(&mut world, &registry, &mut scheduler, 400);
+
+        assert_eq!(level(&world, &registry, 1, 0, 0), Some(0));
+        assert_eq!(level(&world, &registry, 1, 0, 8), Some(1));
+        assert_eq!(world.get_block_light(&Position::new(1, 0, 8)), 15);
+    }
+
+    #[test]
+    fn the_same_edits_give_the_same_world() {
+        let states = (0..2).map(|_| {
+            let (mut world, registry, mut scheduler) = setup();
+            let water = registry.id("water").unwrap();
+            let stone = registry.id("stone").unwrap();
+
+            scheduler.set_block(&mut world, &registry, &Position::new(0, 4, 0), water);
+            for x in -3..=3 {
+                scheduler.set_block(&mut world, &registry, &Position::new(x, 0, 2), stone);
+            }
+            run(&mut world, &registry, &mut scheduler, 23);
+            scheduler.set_block(&mut world, &registry, &Position::new(1, 0, 0), stone);
+            run(&mut world, &registry, &mut scheduler, 50);
+
+            let mut blocks = Vec::new();
+            for x in -12..=12 {
+                for y in 0..6 {
+                    for z in -12..=12 {
+                        blocks.push(world.get_block(&Position::new(x, y, z)));
+                    }
+                }
+            }
+            (blocks, scheduler.current_tick())
+        }).collect::<Vec<_>>();
+
+        assert_eq!(states[0], states[1]);
+    }
+
+    #[test]
+    fn flows_finish_across_chunks_that_were_unloaded_on_the_way() {
+        let flow = |unload: bool| {
+            let (mut world, registry, mut scheduler) = setup();
+            let water = registry.id("water").unwrap();
+            let chunk_position = Position::new(1, 0, 0);
+
+            scheduler.set_block(&mut world, &registry, &Position::new(13, 0, 0), water);
+            run(&mut world, &registry, &mut scheduler, 12);
+            if unload {
+                scheduler.chunk_unloaded(&chunk_position);
+                let chunk = world.remove_chunk(&chunk_position).unwrap();
+                run(&mut world, &registry, &mut scheduler, 50);
+
+                world.add_chunk(chunk);
+                light::light_chunk(&mut world, &registry, &chunk_position);
+                scheduler.chunk_loaded(&mut world, &registry, &chunk_position);
+            }
+            run(&mut world, &registry, &mut scheduler, 200);
+            assert!(scheduler.is_idle());
+
+            let mut levels = Vec::new();
+            for x in 4..=24 {
+                for z in -9..=9 {
+                    levels.push(level(&world, &registry, x, 0, z));
+                }
+            }
+            levels
+        };
+
+        let levels = flow(true);
+        assert_eq!(levels, flow(false));
+        // The flow made it all the way into the chunk that was gone for a while
+        assert_eq!(levels[(20 - 4) * 19 + 9], Some(7));
+    }
+}
@@ -1,55 +1,101 @@
-use crate::{util, world};
+use crate::{behaviour, coords, light, util, world};
 use futures_lite::future;
-use glam::{Mat4, Quat, Vec3};
-use std::borrow::Cow;
+use glam::{Mat4, Vec2, Vec3, Vec4};
 use std::{mem, slice};
-use std::collections::HashSet;
-use std::mem::swap;
+use std::collections::{HashMap, HashSet};
 use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::time::Instant;
 use dolly::drivers::YawPitch;
 use dolly::prelude::{CameraRig, Position, Smooth};
-use wgpu::{include_wgsl, Adapter, Backends, BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource, BindingType, Buffer, BufferBinding, BufferBindingType, BufferSize, Color, CommandEncoderDescriptor, CompositeAlphaMode, Device, DeviceDescriptor, FragmentState, Instance, Limits, LoadOp, Operations, PipelineLayout, PipelineLayoutDescriptor, Queue, RenderPass, RenderPassColorAttachment, RenderPassDescriptor, RenderPipeline, RenderPipelineDescriptor, RequestAdapterOptions, RequestAdapterOptionsBase, ShaderModule, ShaderModuleDescriptor, ShaderSource, ShaderStages, Surface, SurfaceConfiguration, TextureUsages, TextureViewDescriptor, VertexState, VertexBufferLayout, VertexStepMode, VertexAttribute, VertexFormat, BufferSlice, IndexFormat, TextureSampleType, TextureViewDimension, SamplerBindingType, SamplerDescriptor, Sampler, BufferAddress, RenderPassDepthStencilAttachment, TextureDescriptor, Extent3d, TextureDimension, TextureFormat, TextureAspect, Texture, TextureView, DepthStencilState, CompareFunction, PrimitiveState, PolygonMode};
+use wgpu::{include_wgsl, Backends, BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource, BindingType, Buffer, BufferBinding, BufferBindingType, BufferSize, Color, CommandEncoderDescriptor, CompositeAlphaMode, Device, DeviceDescriptor, FragmentState, Instance, Limits, BlendState, ColorTargetState, ColorWrites, LoadOp, Operations, PipelineLayout, PipelineLayoutDescriptor, Queue, RenderPassColorAttachment, RenderPassDescriptor, RenderPipeline, RenderPipelineDescriptor, RequestAdapterOptions, ShaderModule, ShaderStages, Surface, SurfaceConfiguration, TextureUsages, TextureViewDescriptor, VertexState, VertexBufferLayout, VertexStepMode, VertexAttribute, VertexFormat, IndexFormat, TextureSampleType, TextureViewDimension, SamplerBindingType, RenderPassDepthStencilAttachment, TextureDescriptor, Extent3d, TextureDimension, TextureFormat, TextureAspect, TextureView, DepthStencilState, CompareFunction, PrimitiveState, PolygonMode};
 use winit::dpi::{PhysicalSize, Size};
-use winit::event::{DeviceEvent, ElementState, Event, VirtualKeyCode, WindowEvent};
+use winit::event::{DeviceEvent, ElementState, Event, MouseButton, VirtualKeyCode, WindowEvent};
 use winit::event_loop::{ControlFlow, EventLoop};
 use winit::platform::run_return::EventLoopExtRunReturn;
 use winit::window::{Window, WindowBuilder};
-use crate::texture::Texture2D;
+use crate::atlas::{AtlasConfig, BlockUvs, TextureAtlas, TextureLayers, TextureLayout};
+use crate::mipmap::MipConfig;
+use crate::outline::Outline;
+use crate::texture::{SamplerConfig, Texture2DArray};
 use crate::util::VSInput;
-use crate::world::{BLOCK_TYPE_AIR, BLOCK_TYPE_STONE, Chunk, ChunkBuilder, World};
+use crate::biome::{BiomeConfig, BiomeMap};
+use crate::block::{BlockRegistry, RenderLayer};
+use crate::buffer_builder::BufferBuilder;
+use crate::caves::CaveConfig;
+use crate::fluid::FluidHandler;
+use crate::region::RegionStore;
+use crate::snapshot::ChunkSnapshot;
+use crate::streaming::{ChunkStreamer, StreamingConfig};
+use crate::structures::{StructureConfig, StructurePlacer};
+use crate::tick::{TickConfig, TickScheduler};
+use crate::workers::{ChunkResult, ChunkWorkers};
+use crate::world::{MeshingMode, RaycastHit, World, CHUNK_SIZE_X, CHUNK_SIZE_Y, CHUNK_SIZE_Z};
+use crate::worldgen::{HeightmapGenerator, TerrainBlocks, TerrainConfig};
+
+const WORLD_SEED: u32 = 1337;
+const SAVE_DIRECTORY: &str = "world";
+const TEXTURE_DIRECTORY: &str = "textures";
+const TEXTURE_LAYOUT: TextureLayout = TextureLayout::Array;
+// Slow frames catch up on at most this many ticks, beyond that the world runs slower instead
+const MAX_TICKS_PER_FRAME: u32 = 5;
+// How far away blocks can be picked, in blocks
+const REACH: f32 = 8.0;
+// Blocks the number keys select for placing
+const HOTBAR: [&str; 9] = ["stone", "dirt", "grass", "cobblestone", "log", "leaves", "glass", "sand", "torch"];
 
 pub struct Game {
     event_loop: EventLoop<()>,
     window: Window,
 
     //WGPU
-    instance: Instance,
     surface: Surface,
-    adapter: Adapter,
     device: Device,
     queue: Queue,
 
     surface_config: SurfaceConfiguration,
 
-    shader_module: ShaderModule,
-    bind_group_layout: BindGroupLayout,
-    pipeline_layout: PipelineLayout,
+    // The bind group and pipelines keep the texture, sampler and shaders they were created from alive
     uniform_buffer: Buffer,
     bind_group: BindGroup,
-    pipeline: RenderPipeline,
-    vertex_buffer: Buffer,
-    index_buffer: Buffer,
-    texture: Texture2D,
-    sampler: Sampler,
+    pipelines: [RenderPipeline; 3],
+    outline: Outline,
     camera_rig: CameraRig,
 
-    depth: Texture,
     depth_view: TextureView,
 
+    block_registry: Arc<BlockRegistry>,
+    region_store: RegionStore,
     world: World,
+    scheduler: TickScheduler,
+    // Block the camera looks at, updated every frame
+    target: Option<RaycastHit>,
+    hotbar: Vec<u16>,
+    selected_block: usize,
+    streamer: ChunkStreamer,
+    workers: ChunkWorkers,
+    chunk_meshes: HashMap<world::Position, ChunkMesh>
+}
+
+struct LayerMesh {
+    vertex_buffer: Buffer,
+    index_buffer: Buffer,
     index_count: u32
 }
 
+struct ChunkMesh {
+    layers: [Option<LayerMesh>; 3],
+    // Translucent faces stay on the CPU so they can be sorted again whenever the camera enters another block
+    translucent: BufferBuilder,
+    sorted_from: world::Position
+}
+
+impl Default for Game {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Game {
     pub fn new() -> Self {
         let event_loop = EventLoop::new();
@@ -115,35 +161,37 @@ impl Game {
                 visibility: ShaderStages::FRAGMENT,
                 ty: BindingType::Texture {
                     sample_type: TextureSampleType::Float { filterable: true },
-                    view_dimension: TextureViewDimension::D2,
+                    view_dimension: TextureViewDimension::D2Array,
                     multisampled: false,
                 },
                 count: None,
             }, BindGroupLayoutEntry {
                 binding: 2,
                 visibility: ShaderStages::FRAGMENT,
-                ty: BindingType::Sampler(SamplerBindingType::NonFiltering),
+                ty: BindingType::Sampler(SamplerBindingType::Filtering),
                 count: None,
             }],
         });
 
         let uniform_buffer = util::create_uniform_buffer(&device, mem::size_of::<Mat4>());
 
-        let texture = Texture2D::new(&device, &queue, "texture.png");
-        let sampler = device.create_sampler(&SamplerDescriptor {
-            label: None,
-            address_mode_u: Default::default(),
-            address_mode_v: Default::default(),
-            address_mode_w: Default::default(),
-            mag_filter: Default::default(),
-            min_filter: Default::default(),
-            mipmap_filter: Default::default(),
-            lod_min_clamp: 0.0,
-            lod_max_clamp: 0.0,
-            compare: None,
-            anisotropy_clamp: None,
-            border_color: None,
-        });
+        let block_registry = Arc::new(BlockRegistry::load("blocks.ron"));
+        let (texture, block_uvs) = match TEXTURE_LAYOUT {
+            TextureLayout::Atlas => {
+                let config = AtlasConfig::default();
+                let atlas = TextureAtlas::load(TEXTURE_DIRECTORY, config);
+                let mips = MipConfig { max_levels: config.mip_levels(), alpha: None };
+                (Texture2DArray::new(&device, &queue, slice::from_ref(atlas.image()), &mips), BlockUvs::new(&block_registry, &atlas))
+            }
+            TextureLayout::Array => {
+                let layers = TextureLayers::load(TEXTURE_DIRECTORY);
+                (Texture2DArray::new(&device, &queue, layers.images(), &MipConfig::default()), BlockUvs::from_layers(&block_registry, &layers))
+            }
+        };
+        let block_uvs = Arc::new(block_uvs);
+        // Faces repeat their texture in the shader, wrapping lets filtering at the edge of a whole layer
+        // continue on the other side. Atlas tiles never get close enough to the edge for it to matter.
+        let sampler = SamplerConfig::default().create(&device);
 
         let bind_group = device.create_bind_group(&BindGroupDescriptor {
             label: None,
@@ -170,66 +218,31 @@ impl Game {
             push_constant_ranges: &[],
         });
 
-        let pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
-            label: None,
-            layout: Some(&pipeline_layout),
-            vertex: VertexState {
-                module: &shader_module,
-                entry_point: "vs_main",
-                buffers: &[VertexBufferLayout {
-                    array_stride: mem::size_of::<VSInput>() as _,
-                    step_mode: VertexStepMode::Vertex,
-                    attributes: &[VertexAttribute {
-                        format: VertexFormat::Float32x3,
-                        offset: 0,
-                        shader_location: 0,
-                    }, VertexAttribute {
-                        format: VertexFormat::Float32x2,
-                        offset: mem::size_of::<Vec3>() as _,
-                        shader_location: 1,
-                    }],
-                }],
-            },
-            primitive: PrimitiveState {
-                topology: Default::default(),
-                strip_index_format: None,
-                front_face: Default::default(),
-                cull_mode: None,
-                unclipped_depth: false,
-                polygon_mode: PolygonMode::Fill,
-                conservative: false,
-            },
-            depth_stencil: Some(DepthStencilState {
-                format: TextureFormat::Depth24Plus,
-                depth_write_enabled: true,
-                depth_compare: CompareFunction::LessEqual,
-                stencil: Default::default(),
-                bias: Default::default(),
-            }),
-            multisample: Default::default(),
-            fragment: Some(FragmentState {
-                module: &shader_module,
-                entry_point: "fs_main",
-                targets: &[Some(swapchain_format.into())],
-            }),
-            multiview: None,
-        });
+        let pipelines = RenderLayer::ALL.map(|layer| create_block_pipeline(&device, &pipeline_layout, &shader_module, swapchain_format, layer));
+        let outline = Outline::new(&device, swapchain_format, &uniform_buffer);
 
         let camera_rig = CameraRig::builder()
-            .with(Position::new(Vec3::Y))
+            .with(Position::new(Vec3::new(0.0, 32.0, 0.0)))
             .with(YawPitch::new())
             .with(Smooth::new_position_rotation(0., 0.))
             .build();
 
         surface.configure(&device, &surface_config);
 
-        let chunk = Chunk::new(world::Position::default(), BLOCK_TYPE_STONE);
-
-        let mut world = World::new();
-        world.add_chunk(chunk);
-
-        let mut chunk_builder = ChunkBuilder::new(world.chunks.get(&world::Position::default()).unwrap());
-        let (vertex_buffer, index_buffer, index_count) = chunk_builder.build_mesh(&device, &world);
+        let biomes = BiomeMap::load("biomes.ron", &block_registry, WORLD_SEED, BiomeConfig::default());
+        let structures = StructurePlacer::load("structures.ron", &block_registry, &biomes, WORLD_SEED, StructureConfig::default());
+        let generator = HeightmapGenerator::new(WORLD_SEED, TerrainConfig::default(), TerrainBlocks::from_registry(&block_registry))
+            .with_biomes(biomes)
+            .with_caves(CaveConfig::default())
+            .with_structures(structures);
+        let region_store = RegionStore::new(SAVE_DIRECTORY);
+        let world = World::with_registry(&block_registry);
+        let mut scheduler = TickScheduler::new(WORLD_SEED as u64, TickConfig::default());
+        let fluids = FluidHandler::load("fluids.ron", &block_registry);
+        scheduler.register(fluids.blocks(&block_registry), fluids);
+        behaviour::register_defaults(&mut scheduler, &block_registry);
+        let workers = ChunkWorkers::new(ChunkWorkers::default_thread_count(), block_registry.clone(), block_uvs, Arc::new(generator), region_store.clone(), MeshingMode::Greedy);
+        let hotbar = HOTBAR.iter().filter_map(|name| block_registry.id(name)).collect();
 
         let depth = device.create_texture(&TextureDescriptor {
             label: None,
@@ -259,39 +272,168 @@ impl Game {
         Self {
             event_loop,
             window,
-            instance,
             surface,
-            adapter,
             device,
             queue,
             surface_config,
-            shader_module,
-            bind_group_layout,
-            pipeline_layout,
             uniform_buffer,
             bind_group,
-            pipeline,
-            vertex_buffer,
-            index_buffer,
-            texture,
-            sampler,
+            pipelines,
+            outline,
             camera_rig,
+            block_registry,
+            region_store,
             world,
-            index_count,
-            depth,
+            scheduler,
+            target: None,
+            hotbar,
+            selected_block: 0,
+            streamer: ChunkStreamer::new(StreamingConfig::default()),
+            workers,
+            chunk_meshes: HashMap::new(),
             depth_view
         }
     }
 
+    // Left breaks the targeted block, right places the selected one against the face that was hit. The edits go
+    // through the scheduler like any other, the chunks they touch are meshed again once they are marked dirty.
+    fn use_target(&mut self, button: MouseButton) {
+        let Some(target) = self.target else {
+            return;
+        };
+
+        match button {
+            MouseButton::Left => {
+                self.scheduler.set_block(&mut self.world, &self.block_registry, &target.position, world::BLOCK_TYPE_AIR);
+            }
+            MouseButton::Right => {
+                let Some(&block) = self.hotbar.get(self.selected_block) else {
+                    return;
+                };
+
+                // Only into air or fluid, and never into the camera
+                let normal = target.normal;
+                let position = target.position.offset(normal.x, normal.y, normal.z);
+                let replaced = self.world.get_block(&position);
+                let replaceable = replaced == world::BLOCK_TYPE_AIR || self.block_registry.fluid(replaced).is_some();
+                let inside_camera = self.block_registry.is_solid(block) && position == camera_block(self.camera_rig.final_transform.position);
+                if normal != world::Position::default() && replaceable && !inside_camera {
+                    self.scheduler.set_block(&mut self.world, &self.block_registry, &position, block);
+                }
+            }
+            _ => {}
+        }
+        self.update_target();
+    }
+
+    fn update_target(&mut self) {
+        let transform = &self.camera_rig.final_transform;
+        self.target = self.world.raycast(transform.position, transform.forward(), REACH);
+    }
+
+    fn update_chunks(&mut self) {
+        let update = self.streamer.update(self.camera_rig.final_transform.position);
+
+        let mut evicted = Vec::new();
+        for position in update.unload.iter() {
+            self.workers.cancel(position);
+            self.chunk_meshes.remove(position);
+            self.scheduler.chunk_unloaded(position);
+
+            let modified = self.world.is_modified(position);
+            if let Some(chunk) = self.world.remove_chunk(position) {
+                if modified {
+                    evicted.push(chunk);
+                }
+            }
+        }
+
+        // Untouched chunks are regenerated from the seed, so only edits have to be written back
+        if !evicted.is_empty() {
+            self.region_store.save_chunks(evicted.iter()).expect("Failed to save chunks");
+        }
+
+        for position in update.load.iter() {
+            self.workers.load(position);
+        }
+
+        for result in self.workers.poll() {
+            match result {
+                ChunkResult::Loaded(position, chunk) => {
+                    let chunk = chunk.expect("Failed to read saved world");
+                    if self.streamer.mark_loaded(&position) {
+                        self.world.add_chunk(chunk);
+                        light::light_chunk(&mut self.world, &self.block_registry, &position);
+                        self.scheduler.chunk_loaded(&mut self.world, &self.block_registry, &position);
+                    }
+                }
+                ChunkResult::Meshed(position, mut meshes) => {
+                    if meshes.is_empty() || !self.world.chunks.contains_key(&position) {
+                        self.chunk_meshes.remove(&position);
+                        continue;
+                    }
+
+                    let eye = self.camera_rig.final_transform.position;
+                    meshes.layer_mut(RenderLayer::Translucent).sort_quads(eye);
+                    let layers = RenderLayer::ALL.map(|layer| {
+                        let mesh = meshes.layer_mut(layer);
+                        if mesh.is_empty() {
+                            return None;
+                        }
+
+                        let (vertex_buffer, index_buffer, index_count) = mesh.build(&self.device);
+                        Some(LayerMesh {
+                            vertex_buffer,
+                            index_buffer,
+                            index_count
+                        })
+                    });
+
+                    self.chunk_meshes.insert(position, ChunkMesh {
+                        layers,
+                        translucent: mem::take(meshes.layer_mut(RenderLayer::Translucent)),
+                        sorted_from: camera_block(eye)
+                    });
+                }
+            }
+        }
+
+        // Edits made while a chunk is being meshed replace the job, the outdated mesh never gets uploaded
+        for position in self.world.take_dirty_chunks() {
+            if let Some(snapshot) = ChunkSnapshot::capture(&self.world, &position) {
+                self.workers.mesh(snapshot);
+            }
+        }
+    }
+
+    fn sort_translucent_faces(&mut self) {
+        let eye = self.camera_rig.final_transform.position;
+        let block = camera_block(eye);
+
+        for mesh in self.chunk_meshes.values_mut() {
+            if mesh.sorted_from == block {
+                continue;
+            }
+
+            if let Some(layer) = &mesh.layers[RenderLayer::Translucent as usize] {
+                mesh.translucent.sort_quads(eye);
+                mesh.translucent.write_indices(&self.queue, &layer.index_buffer);
+            }
+            mesh.sorted_from = block;
+        }
+    }
+
     fn render(&mut self) {
+        self.sort_translucent_faces();
         let transform = self.camera_rig.final_transform;
 
-        let mut projection_matrix = Mat4::perspective_lh(90., 16. / 9., 0.1, 1000.);
+        let projection_matrix = Mat4::perspective_lh(90., 16. / 9., 0.1, 1000.);
         let vp = projection_matrix * Mat4::look_at_lh(transform.position,
             transform.position + transform.forward(), transform.up());
 
         self.queue.write_buffer(&self.uniform_buffer, 0,
             unsafe { slice::from_raw_parts(&vp as *const Mat4 as *const _, mem::size_of::<Mat4>())});
+        self.outline.update(&self.queue, self.target.as_ref().map(|hit| &hit.position));
 
         let frame = self
             .surface
@@ -326,10 +468,35 @@ impl Game {
             });
 
             render_pass.set_bind_group(0, &self.bind_group, &[]);
-            render_pass.set_pipeline(&self.pipeline);
-            render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
-            render_pass.set_index_buffer(self.index_buffer.slice(..), IndexFormat::Uint32);
-            render_pass.draw_indexed(0..self.index_count, 0, 0..1);
+            for layer in [RenderLayer::Opaque, RenderLayer::Cutout] {
+                render_pass.set_pipeline(&self.pipelines[layer as usize]);
+                for mesh in self.chunk_meshes.values() {
+                    if let Some(mesh) = &mesh.layers[layer as usize] {
+                        render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+                        render_pass.set_index_buffer(mesh.index_buffer.slice(..), IndexFormat::Uint32);
+                        render_pass.draw_indexed(0..mesh.index_count, 0, 0..1);
+                    }
+                }
+            }
+
+            // Translucent chunks are blended farthest first, their faces are already sorted the same way
+            let mut translucent = self.chunk_meshes.iter()
+                .filter_map(|(position, mesh)| {
+                    let center = coords::chunk_origin(position).offset(CHUNK_SIZE_X / 2, CHUNK_SIZE_Y / 2, CHUNK_SIZE_Z / 2);
+                    let center = Vec3::new(center.x as f32, center.y as f32, center.z as f32);
+                    mesh.layers[RenderLayer::Translucent as usize].as_ref().map(|mesh| (center.distance_squared(transform.position), mesh))
+                })
+                .collect::<Vec<_>>();
+            translucent.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+
+            render_pass.set_pipeline(&self.pipelines[RenderLayer::Translucent as usize]);
+            for (_, mesh) in translucent {
+                render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+                render_pass.set_index_buffer(mesh.index_buffer.slice(..), IndexFormat::Uint32);
+                render_pass.draw_indexed(0..mesh.index_count, 0, 0..1);
+            }
+
+            self.outline.draw(&mut render_pass);
         }
 
         self.queue.submit(Some(encoder.finish()));
@@ -342,54 +509,57 @@ impl Game {
         let delta_time = 1. / 120.; //TODO: bad
 
         let mut pressed_keys = HashSet::new();
+        let mut clicks = Vec::new();
+        let mut last_frame = Instant::now();
+        let mut tick_time = 0.0;
 
         while running {
             self.event_loop.run_return(|event, _, control_flow| {
                 *control_flow = ControlFlow::Wait;
                 match event {
-                    Event::WindowEvent { event, window_id } => {
-                        if self.window.id() == window_id {
-                            match event {
-                                WindowEvent::Resized(size) => {
-                                    self.surface_config.width = size.width;
-                                    self.surface_config.height = size.height;
-
-                                    self.surface.configure(&self.device, &self.surface_config);
-                                }
-                                WindowEvent::KeyboardInput { input, .. } => {
-                                    if let Some(key_code) = input.virtual_keycode {
-                                        if key_code == VirtualKeyCode::Escape {
-                                            running = false;
-                                        }
+                    Event::WindowEvent { event, window_id } if self.window.id() == window_id => {
+                        match event {
+                            WindowEvent::Resized(size) => {
+                                self.surface_config.width = size.width;
+                                self.surface_config.height = size.height;
+
+                                self.surface.configure(&self.device, &self.surface_config);
+                            }
+                            WindowEvent::KeyboardInput { input, .. } => {
+                                if let Some(key_code) = input.virtual_keycode {
+                                    if key_code == VirtualKeyCode::Escape {
+                                        running = false;
+                                    }
+                                    if let Some(slot) = hotbar_slot(key_code) {
+                                        self.selected_block = slot;
+                                    }
 
-                                        match input.state {
-                                            ElementState::Pressed => {
-                                                if !pressed_keys.contains(&key_code) {
-                                                    pressed_keys.insert(key_code);
-                                                }
+                                    match input.state {
+                                        ElementState::Pressed => {
+                                            if !pressed_keys.contains(&key_code) {
+                                                pressed_keys.insert(key_code);
                                             }
-                                            ElementState::Released => {
-                                                if pressed_keys.contains(&key_code) {
-                                                    pressed_keys.remove(&key_code);
-                                                }
+                                        }
+                                        ElementState::Released => {
+                                            if pressed_keys.contains(&key_code) {
+                                                pressed_keys.remove(&key_code);
                                             }
                                         }
                                     }
                                 }
-                                WindowEvent::CloseRequested => running = false,
-                                _ => {}
                             }
+                            WindowEvent::MouseInput { state: ElementState::Pressed, button, .. } => clicks.push(button),
+                            WindowEvent::CloseRequested => running = false,
+                            _ => {}
                         }
                     }
                     Event::MainEventsCleared => {
                         *control_flow = ControlFlow::Exit;
                     }
-                    Event::DeviceEvent { event, ..} => {
-                        if let DeviceEvent::MouseMotion { delta } = event {
-                            let rig = &mut self.camera_rig;
-                            rig.driver_mut::<YawPitch>().rotate_yaw_pitch(delta.0 as _, -delta.1 as _);
-                            rig.update(delta_time);
-                        }
+                    Event::DeviceEvent { event: DeviceEvent::MouseMotion { delta }, ..} => {
+                        let rig = &mut self.camera_rig;
+                        rig.driver_mut::<YawPitch>().rotate_yaw_pitch(delta.0 as _, -delta.1 as _);
+                        rig.update(delta_time);
                     }
 
                     _ => {}
@@ -422,7 +592,119 @@ impl Game {
             camera_rig.driver_mut::<Position>().translate(-delta_pos * delta_time * 10.0);
             camera_rig.update(delta_time);
 
+            let now = Instant::now();
+            tick_time += (now - last_frame).as_secs_f64();
+            last_frame = now;
+
+            let tick_length = 1.0 / self.scheduler.config().ticks_per_second;
+            let mut ticks = 0;
+            while tick_time >= tick_length && ticks < MAX_TICKS_PER_FRAME {
+                self.scheduler.tick(&mut self.world, &self.block_registry);
+                tick_time -= tick_length;
+                ticks += 1;
+            }
+            tick_time = tick_time.min(tick_length);
+
+            self.update_target();
+            for button in clicks.drain(..) {
+                self.use_target(button);
+            }
+
+            self.update_chunks();
             self.render();
         }
+
+        // Like on eviction, only the edited chunks are written back
+        let modified = self.world.chunks.values().filter(|chunk| self.world.is_modified(chunk.position()));
+        self.region_store.save_chunks(modified).expect("Failed to save world");
     }
 }
+
+fn create_block_pipeline(device: &Device, pipeline_layout: &PipelineLayout, shader_module: &ShaderModule, format: TextureFormat, layer: RenderLayer) -> RenderPipeline {
+    // Translucent blocks blend over what is behind them and must not hide it from later translucent faces
+    let translucent = layer == RenderLayer::Translucent;
+    let blend = if translucent { Some(BlendState::ALPHA_BLENDING) } else { None };
+
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: None,
+        layout: Some(pipeline_layout),
+        vertex: VertexState {
+            module: shader_module,
+            entry_point: "vs_main",
+            buffers: &[VertexBufferLayout {
+                array_stride: mem::size_of::<VSInput>() as _,
+                step_mode: VertexStepMode::Vertex,
+                attributes: &[VertexAttribute {
+                    format: VertexFormat::Float32x3,
+                    offset: 0,
+                    shader_location: 0,
+                }, VertexAttribute {
+                    format: VertexFormat::Float32x2,
+                    offset: mem::size_of::<Vec3>() as _,
+                    shader_location: 1,
+                }, VertexAttribute {
+                    format: VertexFormat::Float32,
+                    offset: (mem::size_of::<Vec3>() + mem::size_of::<Vec2>()) as _,
+                    shader_location: 2,
+                }, VertexAttribute {
+                    format: VertexFormat::Float32,
+                    offset: (mem::size_of::<Vec3>() + mem::size_of::<Vec2>() + mem::size_of::<f32>()) as _,
+                    shader_location: 3,
+                }, VertexAttribute {
+                    format: VertexFormat::Float32,
+                    offset: (mem::size_of::<Vec3>() + mem::size_of::<Vec2>() + mem::size_of::<f32>() * 2) as _,
+                    shader_location: 4,
+                }, VertexAttribute {
+                    format: VertexFormat::Float32x4,
+                    offset: (mem::size_of::<Vec3>() + mem::size_of::<Vec2>() + mem::size_of::<f32>() * 3) as _,
+                    shader_location: 5,
+                }, VertexAttribute {
+                    format: VertexFormat::Uint32,
+                    offset: (mem::size_of::<Vec3>() + mem::size_of::<Vec2>() + mem::size_of::<f32>() * 3 + mem::size_of::<Vec4>()) as _,
+                    shader_location: 6,
+                }],
+            }],
+        },
+        primitive: PrimitiveState {
+            topology: Default::default(),
+            strip_index_format: None,
+            front_face: Default::default(),
+            cull_mode: None,
+            unclipped_depth: false,
+            polygon_mode: PolygonMode::Fill,
+            conservative: false,
+        },
+        depth_stencil: Some(DepthStencilState {
+            format: TextureFormat::Depth24Plus,
+            depth_write_enabled: !translucent,
+            depth_compare: CompareFunction::LessEqual,
+            stencil: Default::default(),
+            bias: Default::default(),
+        }),
+        multisample: Default::default(),
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point: if layer == RenderLayer::Cutout { "fs_cutout" } else { "fs_main" },
+            targets: &[Some(ColorTargetState {
+                format,
+                blend,
+                write_mask: ColorWrites::ALL,
+            })],
+        }),
+        multiview: None,
+    })
+}
+
+fn hotbar_slot(key_code: VirtualKeyCode) -> Option<usize> {
+    let keys = [
+        VirtualKeyCode::Key1, VirtualKeyCode::Key2, VirtualKeyCode::Key3,
+        VirtualKeyCode::Key4, VirtualKeyCode::Key5, VirtualKeyCode::Key6,
+        VirtualKeyCode::Key7, VirtualKeyCode::Key8, VirtualKeyCode::Key9
+    ];
+    keys.iter().position(|key| *key == key_code)
+}
+
+fn camera_block(eye: Vec3) -> world::Position {
+    let block = eye.floor();
+    world::Position::new(block.x as i64, block.y as i64, block.z as i64)
+}
@@ -0,0 +1,24 @@
+pub mod game;
+pub mod util;
+pub mod texture;
+pub mod mipmap;
+pub mod atlas;
+pub mod world;
+pub mod block;
+pub mod palette;
+pub mod worldgen;
+pub mod caves;
+pub mod biome;
+pub mod structures;
+pub mod random;
+pub mod region;
+pub mod streaming;
+pub mod coords;
+pub mod buffer_builder;
+pub mod snapshot;
+pub mod workers;
+pub mod light;
+pub mod outline;
+pub mod tick;
+pub mod fluid;
+pub mod behaviour;
@@ -0,0 +1,478 @@
+use std::collections::VecDeque;
+use crate::block::{BlockRegistry, Face};
+use crate::coords;
+use crate::world::{Position, World, CHUNK_SIZE_X, CHUNK_SIZE_Y, CHUNK_SIZE_Z};
+
+pub const MAX_LIGHT: u8 = 15;
+
+// Light spreads through everything that is not opaque and loses one level per block. It is flood filled
+// breadth first, and only ever into loaded chunks. Block light starts at emitting blocks, skylight at full
+// strength in every block above the highest opaque block of its column, so it passes straight down through
+// transparent blocks and falls off sideways under overhangs.
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Channel {
+    Block,
+    Sky
+}
+
+impl Channel {
+    const ALL: [Self; 2] = [Self::Block, Self::Sky];
+
+    fn get(self, world: &World, position: &Position) -> u8 {
+        match self {
+            Self::Block => world.get_block_light(position),
+            Self::Sky => world.get_sky_light(position)
+        }
+    }
+
+    fn set(self, world: &mut World, position: &Position, level: u8) -> bool {
+        match self {
+            Self::Block => world.set_block_light(position, level),
+            Self::Sky => world.set_sky_light(position, level)
+        }
+    }
+
+    fn emission(self, world: &World, registry: &BlockRegistry, position: &Position) -> u8 {
+        match self {
+            Self::Block => registry.light_emission(world.get_block(position)),
+            Self::Sky if is_exposed(world, position) => MAX_LIGHT,
+            Self::Sky => 0
+        }
+    }
+}
+
+// Sets a block and updates the light around it incrementally
+pub fn set_block(world: &mut World, registry: &BlockRegistry, position: &Position, block: u16) {
+    if world.get_block(position) == block {
+        return;
+    }
+
+    // Blocks set outside the loaded chunks bring in a new chunk, which is lit as a whole
+    let chunk_position = coords::world_to_chunk(position);
+    if !world.chunks.contains_key(&chunk_position) {
+        world.set_block(position, block);
+        light_chunk(world, registry, &chunk_position);
+        return;
+    }
+
+    let previous_surface = world.surface_height(position.x, position.z);
+    world.set_block(position, block);
+    let surface = world.surface_height(position.x, position.z);
+
+    for channel in Channel::ALL {
+        // Take away whatever light the old block had or let through, then let the surroundings fill it back in
+        let previous = channel.get(world, position);
+        channel.set(world, position, 0);
+        let mut removals = VecDeque::from([(*position, previous)]);
+        let mut additions = VecDeque::new();
+
+        // Placing or removing the top of a column changes which blocks below it see the sky
+        if channel == Channel::Sky && surface != previous_surface {
+            let bottom = world.column_bottom(position.x, position.z).unwrap_or(position.y);
+            let low = previous_surface.min(surface).map_or(bottom, |height| height + 1).max(bottom);
+            let high = previous_surface.max(surface).unwrap_or(position.y);
+
+            for y in low..=high {
+                let column_position = Position::new(position.x, y, position.z);
+                if is_exposed(world, &column_position) {
+                    if channel.set(world, &column_position, MAX_LIGHT) {
+                        additions.push_back(column_position);
+                    }
+                } else {
+                    let level = channel.get(world, &column_position);
+                    if level > 0 {
+                        channel.set(world, &column_position, 0);
+                        removals.push_back((column_position, level));
+                    }
+                }
+            }
+        }
+
+        additions.extend(remove_light(world, registry, channel, removals));
+
+        let emission = channel.emission(world, registry, position);
+        if emission > 0 && channel.set(world, position, emission) {
+            additions.push_back(*position);
+        }
+
+        spread_light(world, registry, channel, additions);
+    }
+}
+
+// Lights a freshly added chunk from its own emitters, the sky and the light at the borders of its neighbours
+pub fn light_chunk(world: &mut World, registry: &BlockRegistry, chunk_position: &Position) {
+    if !world.chunks.contains_key(chunk_position) {
+        return;
+    }
+
+    let origin = coords::chunk_origin(chunk_position);
+    let mut block_queue = emitters(world, registry, chunk_position);
+    let (sky_removals, mut sky_queue) = light_sky(world, &origin);
+    sky_queue.extend(remove_light(world, registry, Channel::Sky, sky_removals));
+
+    for face in Face::ALL {
+        for position in border(&origin, face) {
+            if world.get_block_light(&position) > 0 {
+                block_queue.push_back(position);
+            }
+            if world.get_sky_light(&position) > 0 {
+                sky_queue.push_back(position);
+            }
+        }
+    }
+
+    spread_light(world, registry, Channel::Block, block_queue);
+    spread_light(world, registry, Channel::Sky, sky_queue);
+}
+
+fn emitters(world: &mut World, registry: &BlockRegistry, chunk_position: &Position) -> VecDeque<Position> {
+    let chunk = &world.chunks[chunk_position];
+    let mut emitters = Vec::new();
+
+    if chunk.storage().palette().iter().any(|block| registry.light_emission(*block) > 0) {
+        for x in 0..CHUNK_SIZE_X {
+            for y in 0..CHUNK_SIZE_Y {
+                for z in 0..CHUNK_SIZE_Z {
+                    let local_position = Position::new(x, y, z);
+                    let emission = registry.light_emission(chunk.get_block(&local_position));
+                    if emission > 0 {
+                        emitters.push((coords::local_to_world(chunk_position, &local_position), emission));
+                    }
+                }
+            }
+        }
+    }
+
+    let mut queue = VecDeque::new();
+    for (position, emission) in emitters {
+        world.set_block_light(&position, emission);
+        queue.push_back(position);
+    }
+    queue
+}
+
+// Fills the exposed part of every column of a new chunk with full skylight and darkens the loaded chunks
+// below that it now covers. Returns the darkened blocks with their old levels and the lit blocks that light
+// can still spread from, blocks surrounded by equally lit ones are left out.
+fn light_sky(world: &mut World, origin: &Position) -> (VecDeque<(Position, u8)>, VecDeque<Position>) {
+    let mut removals = VecDeque::new();
+    let mut additions = VecDeque::new();
+
+    let size_x = CHUNK_SIZE_X + 2;
+    let mut surfaces = Vec::with_capacity(((CHUNK_SIZE_X + 2) * (CHUNK_SIZE_Z + 2)) as usize);
+    for z in -1..=CHUNK_SIZE_Z {
+        for x in -1..=CHUNK_SIZE_X {
+            surfaces.push(world.surface_height(origin.x + x, origin.z + z).unwrap_or(i64::MIN));
+        }
+    }
+    let surface = |x: i64, z: i64| surfaces[((z + 1) * size_x + x + 1) as usize];
+
+    let chunk_position = coords::world_to_chunk(origin);
+    for x in 0..CHUNK_SIZE_X {
+        for z in 0..CHUNK_SIZE_Z {
+            let height = surface(x, z);
+            let shaded_up_to = [surface(x - 1, z), surface(x + 1, z), surface(x, z - 1), surface(x, z + 1)]
+                .into_iter()
+                .max()
+                .unwrap();
+            let lowest = height.saturating_add(1).max(origin.y);
+
+            let chunk = world.chunks.get_mut(&chunk_position).unwrap();
+            for y in lowest..origin.y + CHUNK_SIZE_Y {
+                chunk.set_sky_light(&Position::new(x, y - origin.y, z), MAX_LIGHT);
+                if y == lowest || y <= shaded_up_to {
+                    additions.push_back(Position::new(origin.x + x, y, origin.z + z));
+                }
+            }
+
+            // Blocks below used to see the sky through this chunk before it was loaded
+            if height >= origin.y {
+                let mut below = Position::new(origin.x + x, origin.y - 1, origin.z + z);
+                while world.get_sky_light(&below) == MAX_LIGHT {
+                    world.set_sky_light(&below, 0);
+                    removals.push_back((below, MAX_LIGHT));
+                    below = below.offset(0, -1, 0);
+                }
+            }
+        }
+    }
+
+    (removals, additions)
+}
+
+fn is_exposed(world: &World, position: &Position) -> bool {
+    world.surface_height(position.x, position.z).is_none_or(|height| position.y > height)
+}
+
+fn spread_light(world: &mut World, registry: &BlockRegistry, channel: Channel, mut queue: VecDeque<Position>) {
+    while let Some(position) = queue.pop_front() {
+        let level = channel.get(world, &position);
+        if level <= 1 {
+            continue;
+        }
+
+        for face in Face::ALL {
+            let normal = face.normal();
+            let neighbour = position.offset(normal.x, normal.y, normal.z);
+            if registry.is_opaque(world.get_block(&neighbour)) || channel.get(world, &neighbour) >= level - 1 {
+                continue;
+            }
+
+            if channel.set(world, &neighbour, level - 1) {
+                queue.push_back(neighbour);
+            }
+        }
+    }
+}
+
+// Darkens everything that was lit by the queued positions, each paired with the level it used to have.
+// Returns the lit positions bordering the darkened area, spreading from them again fills the gap back in.
+fn remove_light(world: &mut World, registry: &BlockRegistry, channel: Channel, mut queue: VecDeque<(Position, u8)>) -> VecDeque<Position> {
+    let mut additions = VecDeque::new();
+
+    while let Some((position, level)) = queue.pop_front() {
+        for face in Face::ALL {
+            let normal = face.normal();
+            let neighbour = position.offset(normal.x, normal.y, normal.z);
+            let neighbour_level = channel.get(world, &neighbour);
+
+            if neighbour_level != 0 && neighbour_level < level {
+                channel.set(world, &neighbour, 0);
+                queue.push_back((neighbour, neighbour_level));
+
+                // Sources are darkened like everything else but immediately light up again
+                let emission = channel.emission(world, registry, &neighbour);
+                if emission > 0 {
+                    channel.set(world, &neighbour, emission);
+                    additions.push_back(neighbour);
+                }
+            } else if neighbour_level >= level && neighbour_level > 0 {
+                additions.push_back(neighbour);
+            }
+        }
+    }
+
+    additions
+}
+
+// Blocks just outside a chunk on the given side
+fn border(origin: &Position, face: Face) -> Vec<Position> {
+    let normal = face.normal();
+    let start = |axis: i64, size: i64| match axis {
+        -1 => -1..0,
+        1 => size..size + 1,
+        _ => 0..size
+    };
+
+    let mut positions = Vec::new();
+    for x in start(normal.x, CHUNK_SIZE_X) {
+        for y in start(normal.y, CHUNK_SIZE_Y) {
+            for z in start(normal.z, CHUNK_SIZE_Z) {
+                positions.push(origin.offset(x, y, z));
+            }
+        }
+    }
+    positions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::Random;
+    use crate::world::{Chunk, BLOCK_TYPE_AIR};
+
+    fn open_world(registry: &BlockRegistry, from: Position, to: Position) -> World {
+        let mut world = World::with_registry(registry);
+        for x in from.x..=to.x {
+            for y in from.y..=to.y {
+                for z in from.z..=to.z {
+                    world.add_chunk(Chunk::new(Position::new(x, y, z), BLOCK_TYPE_AIR));
+                    light_chunk(&mut world, registry, &Position::new(x, y, z));
+                }
+            }
+        }
+        world
+    }
+
+    fn light_from_scratch(world: &World, registry: &BlockRegistry) -> World {
+        let mut lit = World::with_registry(registry);
+        for chunk in world.chunks.values() {
+            lit.add_chunk(Chunk::from_storage(*chunk.position(), chunk.storage().clone()));
+        }
+
+        let positions = lit.chunks.keys().copied().collect::<Vec<_>>();
+        for position in positions.iter() {
+            light_chunk(&mut lit, registry, position);
+        }
+        lit
+    }
+
+    #[test]
+    fn light_falls_off_across_chunk_borders() {
+        let registry = BlockRegistry::load("blocks.ron");
+        let torch = registry.id("torch").unwrap();
+        let mut world = open_world(&registry, Position::new(-1, -1, -1), Position::new(1, 0, 0));
+
+        set_block(&mut world, &registry, &Position::new(-1, -1, -1), torch);
+
+        assert_eq!(world.get_block_light(&Position::new(-1, -1, -1)), 14);
+        assert_eq!(world.get_block_light(&Position::new(0, -1, -1)), 13);
+        assert_eq!(world.get_block_light(&Position::new(2, -3, -1)), 9);
+        assert_eq!(world.get_block_light(&Position::new(-14, -1, -1)), 1);
+        assert_eq!(world.get_block_light(&Position::new(-15, -1, -1)), 0);
+
+        // Nothing is stored for chunks that are not loaded
+        assert_eq!(world.get_block_light(&Position::new(-1, -1, 16)), 0);
+        assert!(!world.chunks.contains_key(&Position::new(-1, -1, 1)));
+    }
+
+    #[test]
+    fn removing_an_emitter_darkens_its_surroundings() {
+        let registry = BlockRegistry::load("blocks.ron");
+        let torch = registry.id("torch").unwrap();
+        let mut world = open_world(&registry, Position::new(-1, -1, -1), Position::new(0, 0, 0));
+
+        set_block(&mut world, &registry, &Position::new(3, 3, 3), torch);
+        set_block(&mut world, &registry, &Position::new(-8, 3, 3), torch);
+        set_block(&mut world, &registry, &Position::new(3, 3, 3), BLOCK_TYPE_AIR);
+
+        // What is left is only the light of the remaining torch
+        assert_eq!(world.get_block_light(&Position::new(3, 3, 3)), 3);
+        assert_eq!(world.get_block_light(&Position::new(6, 3, 3)), 0);
+        assert_eq!(world.get_block_light(&Position::new(-8, 3, 3)), 14);
+    }
+
+    #[test]
+    fn opaque_blocks_make_light_go_around() {
+        let registry = BlockRegistry::load("blocks.ron");
+        let torch = registry.id("torch").unwrap();
+        let stone = registry.id("stone").unwrap();
+        let mut world = open_world(&registry, Position::new(0, 0, 0), Position::new(0, 0, 0));
+
+        set_block(&mut world, &registry, &Position::new(4, 4, 4), torch);
+        assert_eq!(world.get_block_light(&Position::new(6, 4, 4)), 12);
+
+        // A wall in the x = 5 plane with a single gap three blocks above the torch
+        for y in 0..CHUNK_SIZE_Y {
+            for z in 0..CHUNK_SIZE_Z {
+                if (y, z) != (7, 4) {
+                    set_block(&mut world, &registry, &Position::new(5, y, z), stone);
+                }
+            }
+        }
+
+        assert_eq!(world.get_block_light(&Position::new(5, 4, 4)), 0);
+        assert_eq!(world.get_block_light(&Position::new(6, 4, 4)), 14 - 8);
+        assert_eq!(world.get_block_light(&Position::new(4, 4, 4)), 14);
+    }
+
+    #[test]
+    fn new_chunks_pick_up_light_from_their_neighbours() {
+        let registry = BlockRegistry::load("blocks.ron");
+        let mut world = open_world(&registry, Position::new(0, 0, 0), Position::new(0, 0, 0));
+        set_block(&mut world, &registry, &Position::new(15, 0, 0), registry.id("lava").unwrap());
+
+        let mut chunk = Chunk::new(Position::new(1, 0, 0), BLOCK_TYPE_AIR);
+        chunk.set_block(registry.id("torch").unwrap(), &Position::new(0, 15, 15));
+        world.add_chunk(chunk);
+        light_chunk(&mut world, &registry, &Position::new(1, 0, 0));
+
+        // Light flows both into the new chunk and out of it
+        assert_eq!(world.get_block_light(&Position::new(16, 0, 0)), 14);
+        assert_eq!(world.get_block_light(&Position::new(16, 15, 15)), 14);
+        assert_eq!(world.get_block_light(&Position::new(15, 15, 15)), 13);
+    }
+
+    #[test]
+    fn incremental_updates_match_lighting_from_scratch() {
+        let registry = BlockRegistry::load("blocks.ron");
+        let blocks = ["stone", "torch", "lava", "leaves", "air", "air"].map(|name| registry.id(name).unwrap());
+        let mut world = open_world(&registry, Position::new(-1, -1, -1), Position::new(0, 0, 0));
+        let mut random = Random::new(7);
+
+        for step in 0..600 {
+            let position = Position::new(
+                random.next_below(32) as i64 - 16,
+                random.next_below(32) as i64 - 16,
+                random.next_below(32) as i64 - 16
+            );
+            let block = blocks[random.next_below(blocks.len() as u64) as usize];
+            set_block(&mut world, &registry, &position, block);
+
+            if step % 200 == 199 {
+                let expected = light_from_scratch(&world, &registry);
+                for chunk in world.chunks.values() {
+                    for x in 0..CHUNK_SIZE_X {
+                        for y in 0..CHUNK_SIZE_Y {
+                            for z in 0..CHUNK_SIZE_Z {
+                                let position = coords::local_to_world(chunk.position(), &Position::new(x, y, z));
+                                assert_eq!(world.get_block_light(&position), expected.get_block_light(&position), "{:?} after {} edits", position, step + 1);
+                                assert_eq!(world.get_sky_light(&position), expected.get_sky_light(&position), "{:?} after {} edits", position, step + 1);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn skylight_falls_off_under_overhangs() {
+        let registry = BlockRegistry::load("blocks.ron");
+        let stone = registry.id("stone").unwrap();
+        let mut world = open_world(&registry, Position::new(0, -1, 0), Position::new(0, 0, 0));
+        assert_eq!(world.get_sky_light(&Position::new(3, -16, 3)), 15);
+
+        // A floor across the whole chunk and a roof over half of it
+        for x in 0..CHUNK_SIZE_X {
+            for z in 0..CHUNK_SIZE_Z {
+                set_block(&mut world, &registry, &Position::new(x, -1, z), stone);
+                if x < 8 {
+                    set_block(&mut world, &registry, &Position::new(x, 5, z), stone);
+                }
+            }
+        }
+
+        assert_eq!(world.get_sky_light(&Position::new(8, 0, 8)), 15);
+        assert_eq!(world.get_sky_light(&Position::new(7, 0, 8)), 14);
+        assert_eq!(world.get_sky_light(&Position::new(0, 4, 8)), 7);
+        assert_eq!(world.get_sky_light(&Position::new(3, -5, 3)), 0);
+
+        // Taking the roof off lets the sky straight in again
+        for x in 0..8 {
+            for z in 0..CHUNK_SIZE_Z {
+                set_block(&mut world, &registry, &Position::new(x, 5, z), BLOCK_TYPE_AIR);
+            }
+        }
+        assert_eq!(world.get_sky_light(&Position::new(0, 0, 8)), 15);
+        assert_eq!(world.get_sky_light(&Position::new(0, -2, 8)), 0);
+    }
+
+    #[test]
+    fn skylight_passes_through_transparent_blocks() {
+        let registry = BlockRegistry::load("blocks.ron");
+        let mut world = open_world(&registry, Position::new(0, 0, 0), Position::new(0, 0, 0));
+
+        set_block(&mut world, &registry, &Position::new(3, 10, 3), registry.id("leaves").unwrap());
+        assert_eq!(world.get_sky_light(&Position::new(3, 10, 3)), 15);
+        assert_eq!(world.get_sky_light(&Position::new(3, 0, 3)), 15);
+
+        set_block(&mut world, &registry, &Position::new(3, 12, 3), registry.id("stone").unwrap());
+        assert_eq!(world.get_sky_light(&Position::new(3, 10, 3)), 14);
+        assert_eq!(world.get_sky_light(&Position::new(3, 13, 3)), 15);
+    }
+
+    #[test]
+    fn chunks_loaded_above_cover_the_ones_below() {
+        let registry = BlockRegistry::load("blocks.ron");
+        let mut world = open_world(&registry, Position::new(0, 0, 0), Position::new(0, 0, 0));
+        assert_eq!(world.get_sky_light(&Position::new(3, 5, 3)), 15);
+
+        world.add_chunk(Chunk::new(Position::new(0, 1, 0), registry.id("stone").unwrap()));
+        light_chunk(&mut world, &registry, &Position::new(0, 1, 0));
+
+        assert_eq!(world.get_sky_light(&Position::new(3, 5, 3)), 0);
+        assert_eq!(world.get_sky_light(&Position::new(3, 15, 3)), 0);
+    }
+}
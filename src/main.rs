@@ -1,12 +1,6 @@
-use crate::game::Game;
-
-mod game;
-mod util;
-mod texture;
-mod world;
-mod buffer_builder;
+use test_engine::game::Game;
 
 fn main() {
-    let mut game = Game::new();
+    let game = Game::new();
     game.run();
 }
@@ -0,0 +1,175 @@
+use image::{Rgba, RgbaImage};
+
+// What happens to alpha when a texture gets smaller
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MipAlpha {
+    // Averaged like the colour channels, right for opaque and translucent textures
+    Blend,
+    // Rescaled on every level so as many texels pass the alpha test as on the full size image, otherwise
+    // cutouts like leaves thin out and vanish in the distance
+    Coverage { cutoff: f32 }
+}
+
+impl MipAlpha {
+    pub const DEFAULT_CUTOFF: f32 = 0.5;
+
+    // Textures with nothing but fully transparent and fully opaque texels are treated as cutouts
+    pub fn detect(image: &RgbaImage) -> Self {
+        let alphas = || image.pixels().map(|pixel| pixel[3]);
+        if alphas().any(|alpha| alpha == 0) && alphas().all(|alpha| alpha == 0 || alpha == 255) {
+            Self::Coverage { cutoff: Self::DEFAULT_CUTOFF }
+        } else {
+            Self::Blend
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct MipConfig {
+    // Levels including the full size image, the chain also stops at 1x1
+    pub max_levels: u32,
+    // Picked per image when not set
+    pub alpha: Option<MipAlpha>
+}
+
+impl Default for MipConfig {
+    fn default() -> Self {
+        Self {
+            max_levels: u32::MAX,
+            alpha: None
+        }
+    }
+}
+
+pub fn level_count(width: u32, height: u32) -> u32 {
+    32 - width.max(height).max(1).leading_zeros()
+}
+
+// Full mip chain of an sRGB image, the first level is the image itself. Every level halves the previous one
+// with a box filter in linear space, sizes round down like the GPU expects them.
+pub fn generate(image: &RgbaImage, config: &MipConfig) -> Vec<RgbaImage> {
+    let alpha = config.alpha.unwrap_or_else(|| MipAlpha::detect(image));
+    let levels = level_count(image.width(), image.height()).min(config.max_levels.max(1));
+    let coverage = match alpha {
+        MipAlpha::Coverage { cutoff } => alpha_coverage(image, cutoff, 1.0),
+        MipAlpha::Blend => 0.0
+    };
+
+    let mut chain = vec![image.clone()];
+    for _ in 1..levels {
+        let mut level = downsample(chain.last().unwrap());
+        if let MipAlpha::Coverage { cutoff } = alpha {
+            scale_alpha_to_coverage(&mut level, cutoff, coverage);
+        }
+        chain.push(level);
+    }
+    chain
+}
+
+fn downsample(image: &RgbaImage) -> RgbaImage {
+    let width = (image.width() / 2).max(1);
+    let height = (image.height() / 2).max(1);
+
+    RgbaImage::from_fn(width, height, |x, y| {
+        let mut color = [0.0; 3];
+        let mut alpha = 0.0;
+        let mut weight = 0.0;
+
+        for source_y in [y * 2, (y * 2 + 1).min(image.height() - 1)] {
+            for source_x in [x * 2, (x * 2 + 1).min(image.width() - 1)] {
+                let pixel = image.get_pixel(source_x, source_y);
+                // Colours are weighted by alpha so transparent texels do not darken the edges of cutouts
+                let pixel_alpha = pixel[3] as f32 / 255.0;
+                for channel in 0..3 {
+                    color[channel] += srgb_to_linear(pixel[channel]) * pixel_alpha;
+                }
+                alpha += pixel_alpha;
+                weight += 1.0;
+            }
+        }
+
+        let color = if alpha > 0.0 { color.map(|channel| linear_to_srgb(channel / alpha)) } else { [0; 3] };
+        Rgba([color[0], color[1], color[2], (alpha / weight * 255.0).round() as u8])
+    })
+}
+
+// Fraction of texels that pass the alpha test once their alpha is multiplied by the scale
+fn alpha_coverage(image: &RgbaImage, cutoff: f32, scale: f32) -> f32 {
+    let passing = image.pixels().filter(|pixel| pixel[3] as f32 / 255.0 * scale >= cutoff).count();
+    passing as f32 / (image.width() * image.height()) as f32
+}
+
+fn scale_alpha_to_coverage(image: &mut RgbaImage, cutoff: f32, coverage: f32) {
+    // Coverage only grows with the scale, so a binary search finds the closest one
+    let (mut low, mut high) = (0.0f32, 4.0f32);
+    for _ in 0..16 {
+        let middle = (low + high) / 2.0;
+        if alpha_coverage(image, cutoff, middle) < coverage {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+
+    for pixel in image.pixels_mut() {
+        pixel[3] = (pixel[3] as f32 * high).round().min(255.0) as u8;
+    }
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let value = value as f32 / 255.0;
+    if value <= 0.04045 { value / 12.92 } else { ((value + 0.055) / 1.055).powf(2.4) }
+}
+
+fn linear_to_srgb(value: f32) -> u8 {
+    let value = if value <= 0.0031308 { value * 12.92 } else { 1.055 * value.powf(1.0 / 2.4) - 0.055 };
+    (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::Random;
+
+    #[test]
+    fn chains_halve_down_to_one_texel() {
+        let image = RgbaImage::from_pixel(16, 4, Rgba([200, 100, 50, 255]));
+        let chain = generate(&image, &MipConfig::default());
+
+        let sizes = chain.iter().map(|level| level.dimensions()).collect::<Vec<_>>();
+        assert_eq!(sizes, [(16, 4), (8, 2), (4, 1), (2, 1), (1, 1)]);
+        assert!(chain.iter().all(|level| level.pixels().all(|pixel| *pixel == Rgba([200, 100, 50, 255]))));
+
+        let limited = generate(&image, &MipConfig { max_levels: 2, alpha: None });
+        assert_eq!(limited.len(), 2);
+    }
+
+    #[test]
+    fn averages_in_linear_space() {
+        let image = RgbaImage::from_fn(2, 2, |x, _| if x == 0 { Rgba([0, 0, 0, 255]) } else { Rgba([255, 255, 255, 255]) });
+        let chain = generate(&image, &MipConfig::default());
+
+        // Half way between black and white is 188 in sRGB, not 128
+        assert_eq!(*chain[1].get_pixel(0, 0), Rgba([188, 188, 188, 255]));
+    }
+
+    #[test]
+    fn cutouts_keep_their_coverage() {
+        let mut random = Random::new(3);
+        let image = RgbaImage::from_fn(64, 64, |_, _| {
+            let alpha = if random.next_below(10) < 3 { 255 } else { 0 };
+            Rgba([40, 120, 30, alpha])
+        });
+        assert_eq!(MipAlpha::detect(&image), MipAlpha::Coverage { cutoff: MipAlpha::DEFAULT_CUTOFF });
+
+        let covered = generate(&image, &MipConfig::default());
+        let blended = generate(&image, &MipConfig { alpha: Some(MipAlpha::Blend), ..MipConfig::default() });
+        let original = alpha_coverage(&image, 0.5, 1.0);
+        for (level, image) in covered.iter().enumerate().take(5) {
+            assert!((alpha_coverage(image, 0.5, 1.0) - original).abs() < 0.1, "Level {} lost its coverage", level);
+        }
+
+        // Plain averaging lets a sparse cutout fade away
+        assert!(alpha_coverage(&blended[4], 0.5, 1.0) < original / 2.0);
+    }
+}
@@ -0,0 +1,192 @@
+use std::{mem, slice};
+use glam::{BVec3, Mat4, Vec3};
+use wgpu::util::{BufferInitDescriptor, DeviceExt};
+use wgpu::{include_wgsl, BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource, BindingType, BlendState, Buffer, BufferBinding, BufferBindingType, BufferDescriptor, BufferSize, BufferUsages, ColorTargetState, ColorWrites, CompareFunction, DepthBiasState, DepthStencilState, Device, FragmentState, IndexFormat, PipelineLayoutDescriptor, PolygonMode, PrimitiveState, PrimitiveTopology, Queue, RenderPass, RenderPipeline, RenderPipelineDescriptor, ShaderStages, TextureFormat, VertexAttribute, VertexBufferLayout, VertexFormat, VertexState, VertexStepMode};
+use crate::world::Position;
+
+// Corners of a block, the lowest bit picks the x side, the next y and the highest z
+const CORNER_COUNT: usize = 8;
+// Two triangles on every face of the cube
+const OUTLINE_INDICES: [u16; 36] = [
+    0, 4, 6, 0, 6, 2,
+    1, 3, 7, 1, 7, 5,
+    0, 1, 5, 0, 5, 4,
+    2, 6, 7, 2, 7, 3,
+    0, 2, 3, 0, 3, 1,
+    4, 5, 7, 4, 7, 6
+];
+
+pub fn outline_vertices(position: &Position) -> [Vec3; CORNER_COUNT] {
+    let min = Vec3::new(position.x as f32, position.y as f32, position.z as f32);
+    let max = min + 1.0;
+    std::array::from_fn(|index| Vec3::select(BVec3::new(index & 1 != 0, index & 2 != 0, index & 4 != 0), max, min))
+}
+
+// The cube around the block the camera looks at, drawn as lines over the terrain. A depth bias pulls the lines
+// towards the camera so they do not fight with the faces they lie on.
+pub struct Outline {
+    pipeline: RenderPipeline,
+    bind_group: BindGroup,
+    vertex_buffer: Buffer,
+    index_buffer: Buffer,
+    shown: Option<Position>
+}
+
+impl Outline {
+    pub fn new(device: &Device, format: TextureFormat, uniform_buffer: &Buffer) -> Self {
+        let shader_module = device.create_shader_module(include_wgsl!("shaders/outline.wgsl"));
+        let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::VERTEX,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: BufferSize::new(mem::size_of::<Mat4>() as _),
+                },
+                count: None,
+            }],
+        });
+
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &bind_group_layout,
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::Buffer(BufferBinding {
+                    buffer: uniform_buffer,
+                    offset: 0,
+                    size: BufferSize::new(mem::size_of::<Mat4>() as _),
+                }),
+            }],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
+            label: None,
+            layout: Some(&pipeline_layout),
+            vertex: VertexState {
+                module: &shader_module,
+                entry_point: "vs_main",
+                buffers: &[VertexBufferLayout {
+                    array_stride: mem::size_of::<Vec3>() as _,
+                    step_mode: VertexStepMode::Vertex,
+                    attributes: &[VertexAttribute {
+                        format: VertexFormat::Float32x3,
+                        offset: 0,
+                        shader_location: 0,
+                    }],
+                }],
+            },
+            primitive: PrimitiveState {
+                topology: PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: Default::default(),
+                cull_mode: None,
+                unclipped_depth: false,
+                polygon_mode: PolygonMode::Line,
+                conservative: false,
+            },
+            // Pulled towards the camera, more so on faces seen at a grazing angle but never by more than a
+            // hundredth of the depth range
+            depth_stencil: Some(DepthStencilState {
+                format: TextureFormat::Depth24Plus,
+                depth_write_enabled: false,
+                depth_compare: CompareFunction::LessEqual,
+                stencil: Default::default(),
+                bias: DepthBiasState {
+                    constant: -2,
+                    slope_scale: -2.0,
+                    clamp: -0.01,
+                },
+            }),
+            multisample: Default::default(),
+            fragment: Some(FragmentState {
+                module: &shader_module,
+                entry_point: "fs_main",
+                targets: &[Some(ColorTargetState {
+                    format,
+                    blend: Some(BlendState::ALPHA_BLENDING),
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            multiview: None,
+        });
+
+        let vertex_buffer = device.create_buffer(&BufferDescriptor {
+            label: None,
+            size: (CORNER_COUNT * mem::size_of::<Vec3>()) as _,
+            usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let index_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: None,
+            contents: unsafe { slice::from_raw_parts(OUTLINE_INDICES.as_ptr().cast(), OUTLINE_INDICES.len() * mem::size_of::<u16>()) },
+            usage: BufferUsages::INDEX,
+        });
+
+        Self {
+            pipeline,
+            bind_group,
+            vertex_buffer,
+            index_buffer,
+            shown: None
+        }
+    }
+
+    // Moves the outline to the targeted block, or hides it when there is none
+    pub fn update(&mut self, queue: &Queue, target: Option<&Position>) {
+        if let Some(position) = target {
+            if self.shown.as_ref() != Some(position) {
+                let vertices = outline_vertices(position);
+                queue.write_buffer(&self.vertex_buffer, 0,
+                    unsafe { slice::from_raw_parts(vertices.as_ptr() as *const u8, vertices.len() * mem::size_of::<Vec3>()) });
+            }
+        }
+        self.shown = target.copied();
+    }
+
+    pub fn draw<'a>(&'a self, render_pass: &mut RenderPass<'a>) {
+        if self.shown.is_none() {
+            return;
+        }
+
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.set_index_buffer(self.index_buffer.slice(..), IndexFormat::Uint16);
+        render_pass.draw_indexed(0..OUTLINE_INDICES.len() as u32, 0, 0..1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use super::*;
+
+    #[test]
+    fn outlines_cover_the_six_faces_of_the_block() {
+        let vertices = outline_vertices(&Position::new(-3, 4, -17));
+        let corners = (0..8).map(|index| Vec3::new(-3.0 + (index & 1) as f32, 4.0 + (index >> 1 & 1) as f32, -17.0 + (index >> 2) as f32));
+        assert!(vertices.iter().copied().eq(corners));
+        assert_eq!(OUTLINE_INDICES.len(), 36);
+
+        // Every pair of triangles spans one whole face, and no face is covered twice
+        let mut faces = HashSet::new();
+        for face in OUTLINE_INDICES.chunks(6) {
+            let corners = face.iter().copied().collect::<HashSet<_>>();
+            assert_eq!(corners.len(), 4);
+
+            let points = corners.iter().map(|corner| vertices[*corner as usize]).collect::<Vec<_>>();
+            let axis = (0..3).find(|axis| points.iter().all(|point| point[*axis] == points[0][*axis])).unwrap();
+            assert!(faces.insert((axis, points[0][axis] as i64)));
+        }
+        assert_eq!(faces.len(), 6);
+    }
+}
@@ -0,0 +1,224 @@
+// Block storage made of a small palette of distinct values plus bit-packed indices into it.
+// A storage holding a single value keeps no index data at all.
+
+#[derive(Debug, Clone)]
+pub struct PalettedStorage {
+    len: usize,
+    palette: Vec<u16>,
+    counts: Vec<usize>,
+    bits_per_entry: u32,
+    words: Vec<u64>
+}
+
+impl PalettedStorage {
+    pub fn new(len: usize, value: u16) -> Self {
+        Self {
+            len,
+            palette: vec![value],
+            counts: vec![len],
+            bits_per_entry: 0,
+            words: Vec::new()
+        }
+    }
+
+    // Rebuilds storage from its serialised parts, rejecting anything that would index outside the palette
+    pub fn from_parts(len: usize, palette: Vec<u16>, bits_per_entry: u32, words: Vec<u64>) -> Result<Self, String> {
+        if palette.is_empty() {
+            return Err("Palette is empty".to_owned());
+        }
+
+        if bits_per_entry == 0 {
+            return match palette.len() {
+                1 => Ok(Self::new(len, palette[0])),
+                entries => Err(format!("Uniform storage with {} palette entries", entries))
+            };
+        }
+
+        if !matches!(bits_per_entry, 1 | 2 | 4 | 8 | 16) || palette.len() > 1 << bits_per_entry {
+            return Err(format!("{} palette entries can't be stored with {} bits", palette.len(), bits_per_entry));
+        }
+
+        let mut storage = Self {
+            len,
+            counts: vec![0; palette.len()],
+            palette,
+            bits_per_entry,
+            words
+        };
+
+        if storage.words.len() != len.div_ceil(storage.entries_per_word()) {
+            return Err(format!("Expected {} words but found {}", len.div_ceil(storage.entries_per_word()), storage.words.len()));
+        }
+
+        for index in 0..len {
+            let palette_index = storage.palette_index(index);
+            match storage.counts.get_mut(palette_index) {
+                Some(count) => *count += 1,
+                None => return Err(format!("Palette index {} out of range", palette_index))
+            }
+        }
+
+        if let Some(uniform) = storage.counts.iter().position(|&count| count == len) {
+            return Ok(Self::new(len, storage.palette[uniform]));
+        }
+
+        Ok(storage)
+    }
+
+    pub fn palette(&self) -> &[u16] {
+        &self.palette
+    }
+
+    pub fn bits_per_entry(&self) -> u32 {
+        self.bits_per_entry
+    }
+
+    pub fn words(&self) -> &[u64] {
+        &self.words
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_uniform(&self) -> bool {
+        self.bits_per_entry == 0
+    }
+
+    pub fn get(&self, index: usize) -> u16 {
+        self.palette[self.palette_index(index)]
+    }
+
+    pub fn set(&mut self, index: usize, value: u16) {
+        assert!(index < self.len, "Index {} out of range for storage of {}", index, self.len);
+
+        let old_index = self.palette_index(index);
+        if self.palette[old_index] == value {
+            return;
+        }
+
+        let new_index = match self.palette.iter().position(|&entry| entry == value) {
+            Some(new_index) => new_index,
+            None => self.insert(value)
+        };
+
+        self.counts[old_index] -= 1;
+        self.counts[new_index] += 1;
+
+        if self.counts[new_index] == self.len {
+            *self = Self::new(self.len, value);
+            return;
+        }
+
+        self.write_index(index, new_index);
+    }
+
+    fn insert(&mut self, value: u16) -> usize {
+        // Reuse a palette slot that is no longer referenced before growing the palette
+        if let Some(free) = self.counts.iter().position(|&count| count == 0) {
+            self.palette[free] = value;
+            return free;
+        }
+
+        self.palette.push(value);
+        self.counts.push(0);
+
+        if self.palette.len() > 1 << self.bits_per_entry {
+            self.grow();
+        }
+
+        self.palette.len() - 1
+    }
+
+    fn grow(&mut self) {
+        let bits_per_entry = match self.bits_per_entry {
+            0 => 1,
+            bits => bits * 2
+        };
+
+        let indices = (0..self.len).map(|index| self.palette_index(index)).collect::<Vec<_>>();
+
+        self.bits_per_entry = bits_per_entry;
+        self.words = vec![0; self.len.div_ceil(self.entries_per_word())];
+
+        for (index, palette_index) in indices.into_iter().enumerate() {
+            self.write_index(index, palette_index);
+        }
+    }
+
+    fn entries_per_word(&self) -> usize {
+        (u64::BITS / self.bits_per_entry) as usize
+    }
+
+    fn palette_index(&self, index: usize) -> usize {
+        if self.bits_per_entry == 0 {
+            return 0;
+        }
+
+        let entries_per_word = self.entries_per_word();
+        let shift = (index % entries_per_word) as u32 * self.bits_per_entry;
+        let mask = (1u64 << self.bits_per_entry) - 1;
+
+        ((self.words[index / entries_per_word] >> shift) & mask) as usize
+    }
+
+    fn write_index(&mut self, index: usize, palette_index: usize) {
+        let entries_per_word = self.entries_per_word();
+        let shift = (index % entries_per_word) as u32 * self.bits_per_entry;
+        let mask = (1u64 << self.bits_per_entry) - 1;
+
+        let word = &mut self.words[index / entries_per_word];
+        *word = (*word & !(mask << shift)) | ((palette_index as u64) << shift);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_flat_storage() {
+        let mut storage = PalettedStorage::new(4096, 0);
+        let mut reference = vec![0u16; 4096];
+        let mut state = 0x2545f4914f6cdd1du64;
+
+        for step in 0..50_000 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+
+            let index = (state % 4096) as usize;
+            let distinct = if step < 25_000 { 300 } else { 3 };
+            let value = ((state >> 32) % distinct) as u16;
+
+            storage.set(index, value);
+            reference[index] = value;
+        }
+
+        for (index, &value) in reference.iter().enumerate() {
+            assert_eq!(storage.get(index), value, "index {}", index);
+        }
+    }
+
+    #[test]
+    fn collapses_to_uniform() {
+        let mut storage = PalettedStorage::new(64, 1);
+        assert!(storage.is_uniform());
+
+        storage.set(10, 2);
+        storage.set(20, 3);
+        assert!(!storage.is_uniform());
+        assert_eq!(storage.get(10), 2);
+        assert_eq!(storage.get(20), 3);
+        assert_eq!(storage.get(30), 1);
+
+        storage.set(10, 1);
+        storage.set(20, 1);
+        assert!(storage.is_uniform());
+        assert_eq!(storage.get(20), 1);
+    }
+}
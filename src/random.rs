@@ -0,0 +1,42 @@
+use crate::world::Position;
+
+// Small deterministic generator (SplitMix64). World generation and simulation derive one from the seed and
+// a position so results never depend on the order things are processed in.
+#[derive(Debug, Clone)]
+pub struct Random {
+    state: u64
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn at(seed: u64, position: &Position, salt: u64) -> Self {
+        let mut hash = seed ^ salt.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        for value in [position.x, position.y, position.z] {
+            hash = mix(hash ^ value as u64);
+        }
+        Self::new(hash)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        mix(self.state)
+    }
+
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub fn next_below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+}
+
+fn mix(value: u64) -> u64 {
+    let mut value = value;
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    value ^ (value >> 31)
+}
@@ -0,0 +1,492 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use crate::palette::PalettedStorage;
+use crate::world::{Chunk, Position, CHUNK_SIZE_XYZ};
+
+// Region files group REGION_SIZE³ chunks. Each file starts with a header holding the format version, the
+// number of saved chunks and a (slot, offset, length) entry for each of them, followed by the zlib compressed
+// chunks. Slots that were never saved take no space, so a region with a single chunk stays small.
+
+pub const REGION_SIZE: i64 = 32;
+pub const REGION_VOLUME: usize = (REGION_SIZE * REGION_SIZE * REGION_SIZE) as usize;
+pub const FORMAT_VERSION: u32 = 2;
+
+const MAGIC: &[u8; 4] = b"WGRG";
+const ENTRY_SIZE: u64 = 12;
+// A chunk with the largest palette and every word in use, with room for zlib's worst case on top
+const MAX_PAYLOAD_SIZE: u64 = (1 + 4 + (u16::MAX as u64 + 1) * 2 + 4 + CHUNK_SIZE_XYZ as u64 * 8) * 2;
+
+pub fn region_of(chunk_position: &Position) -> Position {
+    Position::new(chunk_position.x.div_euclid(REGION_SIZE),
+                  chunk_position.y.div_euclid(REGION_SIZE),
+                  chunk_position.z.div_euclid(REGION_SIZE))
+}
+
+fn slot_of(chunk_position: &Position) -> usize {
+    let x = chunk_position.x.rem_euclid(REGION_SIZE);
+    let y = chunk_position.y.rem_euclid(REGION_SIZE);
+    let z = chunk_position.z.rem_euclid(REGION_SIZE);
+    (z * REGION_SIZE * REGION_SIZE + y * REGION_SIZE + x) as usize
+}
+
+// Magic, version, entry count and the entries
+fn header_size(entries: usize) -> u64 {
+    12 + entries as u64 * ENTRY_SIZE
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+#[derive(Debug, Clone)]
+pub struct RegionStore {
+    directory: PathBuf
+}
+
+impl RegionStore {
+    pub fn new(directory: impl AsRef<Path>) -> Self {
+        Self {
+            directory: directory.as_ref().to_path_buf()
+        }
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    fn region_path(&self, region: &Position) -> PathBuf {
+        self.directory.join(format!("r.{}.{}.{}.region", region.x, region.y, region.z))
+    }
+
+    // Reads a single chunk without touching the rest of its region file
+    pub fn load_chunk(&self, chunk_position: &Position) -> io::Result<Option<Chunk>> {
+        let mut file = match File::open(self.region_path(&region_of(chunk_position))) {
+            Ok(file) => file,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error)
+        };
+
+        read_version(&mut file)?;
+        let entries = read_entries(&mut file)?;
+        let slot = slot_of(chunk_position);
+        let Some((_, offset, length)) = entries.iter().find(|(entry_slot, _, _)| *entry_slot == slot) else {
+            return Ok(None);
+        };
+
+        let payload = read_payload(&mut file, header_size(entries.len()), *offset, *length)?;
+        decode_chunk(*chunk_position, &payload).map(Some)
+    }
+
+    pub fn save_chunks<'a>(&self, chunks: impl IntoIterator<Item = &'a Chunk>) -> io::Result<()> {
+        let mut regions: HashMap<Position, Vec<&Chunk>> = HashMap::new();
+        for chunk in chunks {
+            regions.entry(region_of(chunk.position())).or_default().push(chunk);
+        }
+
+        fs::create_dir_all(&self.directory)?;
+
+        for (region, chunks) in regions {
+            let mut payloads = self.read_payloads(&region)?;
+            for chunk in chunks {
+                payloads[slot_of(chunk.position())] = Some(encode_chunk(chunk)?);
+            }
+
+            self.write_region(&region, &payloads)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn stored_chunks(&self) -> io::Result<Vec<Position>> {
+        let entries = match fs::read_dir(&self.directory) {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(error) => return Err(error)
+        };
+
+        let mut positions = Vec::new();
+        for entry in entries {
+            let name = entry?.file_name();
+            let region = match parse_region_name(&name.to_string_lossy()) {
+                Some(region) => region,
+                None => continue
+            };
+
+            let mut file = File::open(self.region_path(&region))?;
+            read_version(&mut file)?;
+
+            for (slot, _, _) in read_entries(&mut file)? {
+                let slot = slot as i64;
+                positions.push(Position::new(region.x * REGION_SIZE + slot % REGION_SIZE,
+                                             region.y * REGION_SIZE + slot / REGION_SIZE % REGION_SIZE,
+                                             region.z * REGION_SIZE + slot / (REGION_SIZE * REGION_SIZE)));
+            }
+        }
+
+        Ok(positions)
+    }
+
+    fn read_payloads(&self, region: &Position) -> io::Result<Vec<Option<Vec<u8>>>> {
+        let mut payloads = vec![None; REGION_VOLUME];
+
+        let mut file = match File::open(self.region_path(region)) {
+            Ok(file) => file,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(payloads),
+            Err(error) => return Err(error)
+        };
+
+        read_version(&mut file)?;
+
+        let entries = read_entries(&mut file)?;
+        let header_size = header_size(entries.len());
+        for (slot, offset, length) in entries {
+            payloads[slot] = Some(read_payload(&mut file, header_size, offset, length)?);
+        }
+
+        Ok(payloads)
+    }
+
+    fn write_region(&self, region: &Position, payloads: &[Option<Vec<u8>>]) -> io::Result<()> {
+        // Write next to the old file and swap it in, so a failed save never leaves a half written region
+        let path = self.region_path(region);
+        let temporary = path.with_extension("region.tmp");
+
+        {
+            let mut writer = BufWriter::new(File::create(&temporary)?);
+            writer.write_all(MAGIC)?;
+            writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+
+            let saved = payloads.iter().enumerate()
+                .filter_map(|(slot, payload)| Some((slot, payload.as_ref()?)))
+                .collect::<Vec<_>>();
+            writer.write_all(&(saved.len() as u32).to_le_bytes())?;
+
+            let mut offset = header_size(saved.len());
+            for (slot, payload) in saved.iter() {
+                let entry_offset = u32::try_from(offset).map_err(|_| invalid_data("Region file too large".to_owned()))?;
+                writer.write_all(&(*slot as u32).to_le_bytes())?;
+                writer.write_all(&entry_offset.to_le_bytes())?;
+                writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+                offset += payload.len() as u64;
+            }
+
+            for (_, payload) in saved.iter() {
+                writer.write_all(payload)?;
+            }
+
+            writer.flush()?;
+        }
+
+        fs::rename(temporary, path)
+    }
+}
+
+fn parse_region_name(name: &str) -> Option<Position> {
+    let coordinates = name.strip_prefix("r.")?.strip_suffix(".region")?;
+    let mut parts = coordinates.split('.').map(|part| part.parse::<i64>().ok());
+    let position = Position::new(parts.next()??, parts.next()??, parts.next()??);
+
+    match parts.next() {
+        None => Some(position),
+        Some(_) => None
+    }
+}
+
+fn read_version(file: &mut File) -> io::Result<()> {
+    let mut magic = [0; 4];
+    file.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_data("Not a region file".to_owned()));
+    }
+
+    let version = read_u32(file)?;
+    if version != FORMAT_VERSION {
+        return Err(invalid_data(format!("Unsupported region format version {}", version)));
+    }
+
+    Ok(())
+}
+
+fn read_entries(file: &mut File) -> io::Result<Vec<(usize, u32, u32)>> {
+    let count = read_u32(file)? as usize;
+    if count > REGION_VOLUME {
+        return Err(invalid_data(format!("Region with {} chunks", count)));
+    }
+
+    let mut header = vec![0; count * ENTRY_SIZE as usize];
+    file.read_exact(&mut header)?;
+
+    let word = |entry: &[u8], index: usize| u32::from_le_bytes(entry[index * 4..index * 4 + 4].try_into().unwrap());
+    header.chunks_exact(ENTRY_SIZE as usize)
+        .map(|entry| {
+            let slot = word(entry, 0) as usize;
+            if slot >= REGION_VOLUME {
+                return Err(invalid_data(format!("Chunk slot {} outside of the region", slot)));
+            }
+            Ok((slot, word(entry, 1), word(entry, 2)))
+        })
+        .collect()
+}
+
+// The header comes from disk, so the payload it points at is checked to lie within the file before anything is
+// allocated for it
+fn read_payload(file: &mut File, header_size: u64, offset: u32, length: u32) -> io::Result<Vec<u8>> {
+    let (offset, length) = (offset as u64, length as u64);
+    if length > MAX_PAYLOAD_SIZE || offset < header_size || offset + length > file.metadata()?.len() {
+        return Err(invalid_data(format!("Chunk of {} bytes at {} is out of bounds", length, offset)));
+    }
+
+    let mut payload = vec![0; length as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn encode_chunk(chunk: &Chunk) -> io::Result<Vec<u8>> {
+    let storage = chunk.storage();
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+
+    encoder.write_all(&[storage.bits_per_entry() as u8])?;
+    encoder.write_all(&(storage.palette().len() as u32).to_le_bytes())?;
+    for entry in storage.palette() {
+        encoder.write_all(&entry.to_le_bytes())?;
+    }
+
+    encoder.write_all(&(storage.words().len() as u32).to_le_bytes())?;
+    for word in storage.words() {
+        encoder.write_all(&word.to_le_bytes())?;
+    }
+
+    encoder.finish()
+}
+
+fn decode_chunk(position: Position, payload: &[u8]) -> io::Result<Chunk> {
+    let mut decoder = ZlibDecoder::new(payload);
+
+    let mut bits_per_entry = [0; 1];
+    decoder.read_exact(&mut bits_per_entry)?;
+
+    let palette_len = read_u32(&mut decoder)? as usize;
+    if palette_len > u16::MAX as usize + 1 {
+        return Err(invalid_data(format!("Palette of {} entries", palette_len)));
+    }
+
+    let mut palette = Vec::with_capacity(palette_len);
+    for _ in 0..palette_len {
+        let mut entry = [0; 2];
+        decoder.read_exact(&mut entry)?;
+        palette.push(u16::from_le_bytes(entry));
+    }
+
+    let word_count = read_u32(&mut decoder)? as usize;
+    if word_count > CHUNK_SIZE_XYZ as usize {
+        return Err(invalid_data(format!("Chunk with {} words", word_count)));
+    }
+
+    let mut words = Vec::with_capacity(word_count);
+    for _ in 0..word_count {
+        let mut word = [0; 8];
+        decoder.read_exact(&mut word)?;
+        words.push(u64::from_le_bytes(word));
+    }
+
+    let storage = PalettedStorage::from_parts(CHUNK_SIZE_XYZ as usize, palette, bits_per_entry[0] as u32, words)
+        .map_err(|error| invalid_data(format!("Chunk {:?}: {}", position, error)))?;
+
+    Ok(Chunk::from_storage(position, storage))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::Vec3;
+    use crate::block::BlockRegistry;
+    use crate::coords;
+    use crate::world::{World, BLOCK_TYPE_AIR, CHUNK_SIZE_X, CHUNK_SIZE_Y, CHUNK_SIZE_Z};
+
+    fn test_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("test_engine_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn assert_same_blocks(expected: &World, actual: &World) {
+        assert_eq!(expected.chunks.len(), actual.chunks.len());
+
+        for (position, chunk) in expected.chunks.iter() {
+            let loaded = actual.chunks.get(position).unwrap_or_else(|| panic!("Chunk {:?} was not loaded", position));
+            for x in 0..CHUNK_SIZE_X {
+                for y in 0..CHUNK_SIZE_Y {
+                    for z in 0..CHUNK_SIZE_Z {
+                        let local_position = Position::new(x, y, z);
+                        assert_eq!(chunk.get_block(&local_position), loaded.get_block(&local_position));
+                    }
+                }
+            }
+        }
+    }
+
+    fn sample_world() -> World {
+        let mut world = World::new();
+
+        // Chunks on both sides of region borders, including negative regions
+        for chunk_position in [Position::new(0, 0, 0), Position::new(-1, 0, 0), Position::new(31, -1, 32), Position::new(-33, 5, -64)] {
+            world.add_chunk(Chunk::new(chunk_position, 1));
+        }
+
+        for index in 0..3000i64 {
+            let chunk_position = *world.chunks.keys().nth(index as usize % 4).unwrap();
+            let local_position = Position::new(index % 16, index * 7 % 16, index * 13 % 16);
+            world.set_block(&coords::local_to_world(&chunk_position, &local_position), (index % 300) as u16);
+        }
+
+        world.add_chunk(Chunk::new(Position::new(2, 2, 2), BLOCK_TYPE_AIR));
+        world
+    }
+
+    #[test]
+    fn round_trips_worlds() {
+        let directory = test_directory("round_trip");
+        let world = sample_world();
+
+        world.save(&directory).unwrap();
+        let loaded = World::load(&directory, &BlockRegistry::load("blocks.ron")).unwrap();
+        assert_same_blocks(&world, &loaded);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn loaded_worlds_know_their_blocks() {
+        let directory = test_directory("registry");
+        let registry = BlockRegistry::load("blocks.ron");
+        let [stone, water, leaves] = ["stone", "water", "leaves"].map(|name| registry.id(name).unwrap());
+
+        // Stone ground under a pond and a canopy of leaves
+        let mut world = World::with_registry(&registry);
+        world.add_chunk(Chunk::new(Position::default(), BLOCK_TYPE_AIR));
+        for x in 0..CHUNK_SIZE_X {
+            for z in 0..CHUNK_SIZE_Z {
+                world.set_block(&Position::new(x, 2, z), stone);
+                world.set_block(&Position::new(x, 3, z), if x < 8 { water } else { leaves });
+            }
+        }
+        world.save(&directory).unwrap();
+        let loaded = World::load(&directory, &registry).unwrap();
+
+        for (x, z) in [(2, 5), (12, 5)] {
+            assert_eq!(loaded.surface_height(x, z), Some(2));
+            assert_eq!(loaded.surface_height(x, z), world.surface_height(x, z));
+        }
+
+        // Rays pass through the water and stop at the leaves
+        let down = Vec3::new(0.0, -1.0, 0.0);
+        for x in [2.5, 12.5] {
+            let hit = |world: &World| world.raycast(Vec3::new(x, 8.5, 5.5), down, 16.0).map(|hit| hit.position);
+            assert_eq!(hit(&loaded), hit(&world));
+        }
+        assert_eq!(loaded.raycast(Vec3::new(2.5, 8.5, 5.5), down, 16.0).unwrap().position, Position::new(2, 2, 5));
+        assert_eq!(loaded.raycast(Vec3::new(12.5, 8.5, 5.5), down, 16.0).unwrap().position, Position::new(12, 3, 5));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn loads_single_chunks_lazily() {
+        let directory = test_directory("lazy");
+        let world = sample_world();
+        world.save(&directory).unwrap();
+
+        let store = RegionStore::new(&directory);
+        let chunk_position = Position::new(31, -1, 32);
+        let chunk = store.load_chunk(&chunk_position).unwrap().unwrap();
+        assert_eq!(chunk.position(), &chunk_position);
+        assert!(store.load_chunk(&Position::new(30, -1, 32)).unwrap().is_none());
+        assert!(store.load_chunk(&Position::new(500, 0, 0)).unwrap().is_none());
+
+        let mut expected = World::new();
+        expected.add_chunk(world.chunks.get(&chunk_position).unwrap().clone());
+        let mut loaded = World::new();
+        loaded.add_chunk(chunk);
+        assert_same_blocks(&expected, &loaded);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn saving_keeps_other_chunks_in_the_region() {
+        let directory = test_directory("merge");
+        let mut world = sample_world();
+        world.save(&directory).unwrap();
+
+        let mut edited = World::new();
+        let mut chunk = Chunk::new(Position::new(0, 0, 0), BLOCK_TYPE_AIR);
+        chunk.set_block(9, &Position::new(1, 2, 3));
+        edited.add_chunk(chunk.clone());
+        edited.save(&directory).unwrap();
+
+        world.add_chunk(chunk);
+        assert_same_blocks(&world, &World::load(&directory, &BlockRegistry::load("blocks.ron")).unwrap());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn regions_only_store_saved_chunks() {
+        let directory = test_directory("sparse");
+        let store = RegionStore::new(&directory);
+        store.save_chunks([&Chunk::new(Position::new(3, 4, 5), 1)]).unwrap();
+
+        let size = fs::metadata(store.region_path(&Position::default())).unwrap().len();
+        assert!(size < 256, "Region with one chunk takes {} bytes", size);
+        assert_eq!(store.stored_chunks().unwrap(), [Position::new(3, 4, 5)]);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let directory = test_directory("version");
+        sample_world().save(&directory).unwrap();
+
+        let path = RegionStore::new(&directory).region_path(&Position::default());
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        fs::write(&path, bytes).unwrap();
+
+        let error = RegionStore::new(&directory).load_chunk(&Position::default()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn rejects_lengths_past_the_end_of_the_file() {
+        let directory = test_directory("length");
+        sample_world().save(&directory).unwrap();
+
+        let store = RegionStore::new(&directory);
+        let path = store.region_path(&Position::default());
+        let mut bytes = fs::read(&path).unwrap();
+        // The first entry is the chunk at the origin of the region
+        assert_eq!(bytes[12..16], (slot_of(&Position::default()) as u32).to_le_bytes());
+        bytes[20..24].copy_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&path, bytes).unwrap();
+
+        assert_eq!(store.load_chunk(&Position::default()).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(store.save_chunks([&Chunk::new(Position::new(1, 0, 0), 1)]).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -2,19 +2,29 @@
 var<uniform> transform: mat4x4<f32>;
 
 @group(0) @binding(1)
-var tex: texture_2d<f32>;
+var tex: texture_2d_array<f32>;
 
 @group(0) @binding(2)
 var sam: sampler;
 
 struct VSInput {
     @location(0) position: vec3<f32>,
-    @location(1) uv: vec2<f32>
+    @location(1) uv: vec2<f32>,
+    @location(2) ao: f32,
+    @location(3) light: f32,
+    @location(4) sky_light: f32,
+    @location(5) tile: vec4<f32>,
+    @location(6) layer: u32
 }
 
 struct VSOutput {
     @builtin(position) position: vec4<f32>,
-    @location(0) uv: vec2<f32>
+    @location(0) uv: vec2<f32>,
+    @location(1) ao: f32,
+    @location(2) light: f32,
+    @location(3) sky_light: f32,
+    @location(4) tile: vec4<f32>,
+    @location(5) @interpolate(flat) layer: u32
 }
 
 @vertex
@@ -22,11 +32,39 @@ fn vs_main(input: VSInput) -> VSOutput {
     var output: VSOutput;
     output.position = transform * vec4<f32>(input.position, 1.0);
     output.uv = input.uv;
+    output.ao = input.ao;
+    output.light = input.light;
+    output.sky_light = input.sky_light;
+    output.tile = input.tile;
+    output.layer = input.layer;
 
     return output;
 }
 
+fn shade(input: VSOutput) -> vec4<f32> {
+    // Merged faces repeat their tile once per block. The gradients come from the unwrapped coordinates, so
+    // the seams between repeats do not look like a jump across the whole tile.
+    let uv = input.tile.xy + fract(input.uv) * input.tile.zw;
+    let color = textureSampleGrad(tex, sam, uv, i32(input.layer), dpdx(input.uv) * input.tile.zw, dpdy(input.uv) * input.tile.zw);
+    // Every light level is 20% darker than the one above
+    let block_light = pow(0.8, (1.0 - input.light) * 15.0);
+    let sky_light = pow(0.8, (1.0 - input.sky_light) * 15.0);
+    let light = max(block_light, sky_light);
+    let shade = mix(0.4, 1.0, input.ao) * light;
+    return vec4<f32>(color.rgb * shade, color.a);
+}
+
 @fragment
 fn fs_main(input: VSOutput) -> @location(0) vec4<f32> {
-    return textureSample(tex, sam, input.uv);
-}
\ No newline at end of file
+    return shade(input);
+}
+
+// Cutout blocks are either there or not, half transparent texels in smaller mip levels decide by rounding
+@fragment
+fn fs_cutout(input: VSOutput) -> @location(0) vec4<f32> {
+    let color = shade(input);
+    if (color.a < 0.5) {
+        discard;
+    }
+    return vec4<f32>(color.rgb, 1.0);
+}
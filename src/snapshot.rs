@@ -0,0 +1,145 @@
+use crate::world::{Chunk, Position, World, BLOCK_TYPE_AIR, CHUNK_SIZE_X, CHUNK_SIZE_Y, CHUNK_SIZE_Z};
+
+pub const PADDED_SIZE_X: i64 = CHUNK_SIZE_X + 2;
+pub const PADDED_SIZE_Y: i64 = CHUNK_SIZE_Y + 2;
+pub const PADDED_SIZE_Z: i64 = CHUNK_SIZE_Z + 2;
+
+// Immutable copy of a chunk with a one block border taken from all 26 neighbours, enough to mesh, light or
+// shade it without touching the world. Lookups are plain array reads, missing neighbours read as air like
+// they do through World::get_block.
+#[derive(Debug, Clone)]
+pub struct ChunkSnapshot {
+    position: Position,
+    blocks: Vec<u16>,
+    light: Vec<u8>
+}
+
+impl ChunkSnapshot {
+    pub fn capture(world: &World, position: &Position) -> Option<Self> {
+        let chunk = world.chunks.get(position)?;
+        Some(Self::from_chunks(chunk, |neighbour| world.chunks.get(neighbour)))
+    }
+
+    pub fn from_chunks<'a>(chunk: &Chunk, neighbour: impl Fn(&Position) -> Option<&'a Chunk>) -> Self {
+        let position = *chunk.position();
+        let volume = (PADDED_SIZE_X * PADDED_SIZE_Y * PADDED_SIZE_Z) as usize;
+        let mut snapshot = Self {
+            position,
+            blocks: vec![BLOCK_TYPE_AIR; volume],
+            light: vec![0; volume]
+        };
+
+        for offset_x in -1..=1 {
+            for offset_y in -1..=1 {
+                for offset_z in -1..=1 {
+                    let offset = Position::new(offset_x, offset_y, offset_z);
+                    let source = if offset == Position::default() {
+                        Some(chunk)
+                    } else {
+                        neighbour(&position.offset(offset_x, offset_y, offset_z))
+                    };
+
+                    if let Some(source) = source {
+                        snapshot.copy_from(source, &offset);
+                    }
+                }
+            }
+        }
+
+        snapshot
+    }
+
+    pub fn position(&self) -> &Position {
+        &self.position
+    }
+
+    // Local coordinates may reach one block outside the chunk in every direction
+    pub fn get_block(&self, local_position: &Position) -> u16 {
+        self.blocks[Self::index(local_position)]
+    }
+
+    pub fn get_block_light(&self, local_position: &Position) -> u8 {
+        self.light[Self::index(local_position)] & 0x0f
+    }
+
+    pub fn get_sky_light(&self, local_position: &Position) -> u8 {
+        self.light[Self::index(local_position)] >> 4
+    }
+
+    pub fn contains(local_position: &Position) -> bool {
+        (-1..=CHUNK_SIZE_X).contains(&local_position.x)
+            && (-1..=CHUNK_SIZE_Y).contains(&local_position.y)
+            && (-1..=CHUNK_SIZE_Z).contains(&local_position.z)
+    }
+
+    // Copies the slice of a neighbour that borders the chunk, or the whole chunk for the centre
+    fn copy_from(&mut self, chunk: &Chunk, offset: &Position) {
+        let range = |offset: i64, size: i64| match offset {
+            -1 => size - 1..size,
+            0 => 0..size,
+            _ => 0..1
+        };
+
+        for x in range(offset.x, CHUNK_SIZE_X) {
+            for y in range(offset.y, CHUNK_SIZE_Y) {
+                for z in range(offset.z, CHUNK_SIZE_Z) {
+                    let source = Position::new(x, y, z);
+                    let target = Position::new(
+                        x + offset.x * CHUNK_SIZE_X,
+                        y + offset.y * CHUNK_SIZE_Y,
+                        z + offset.z * CHUNK_SIZE_Z
+                    );
+                    let index = Self::index(&target);
+                    self.blocks[index] = chunk.get_block(&source);
+                    self.light[index] = chunk.packed_light(&source);
+                }
+            }
+        }
+    }
+
+    fn index(local_position: &Position) -> usize {
+        debug_assert!(Self::contains(local_position), "{:?} is outside the snapshot", local_position);
+        ((local_position.z + 1) * PADDED_SIZE_X * PADDED_SIZE_Y + (local_position.y + 1) * PADDED_SIZE_X + local_position.x + 1) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coords;
+
+    #[test]
+    fn copies_borders_of_all_neighbours() {
+        let mut world = World::new();
+        let center = Position::new(-1, 0, -3);
+        let origin = coords::chunk_origin(&center);
+
+        // Mark every block of the surrounding chunks with a value derived from its position
+        for x in -1..=CHUNK_SIZE_X {
+            for y in -1..=CHUNK_SIZE_Y {
+                for z in -1..=CHUNK_SIZE_Z {
+                    let value = ((x + 1) + (y + 1) * 3 + (z + 1) * 7) as u16 % 11 + 1;
+                    world.set_block(&origin.offset(x, y, z), value);
+                    world.set_block_light(&origin.offset(x, y, z), value as u8);
+                    world.set_sky_light(&origin.offset(x, y, z), 12 - value as u8);
+                }
+            }
+        }
+        world.remove_chunk(&center.offset(1, 1, 1));
+
+        let snapshot = ChunkSnapshot::capture(&world, &center).unwrap();
+        for x in -1..=CHUNK_SIZE_X {
+            for y in -1..=CHUNK_SIZE_Y {
+                for z in -1..=CHUNK_SIZE_Z {
+                    let local = Position::new(x, y, z);
+                    let world_position = coords::local_to_world(&center, &local);
+                    assert_eq!(snapshot.get_block(&local), world.get_block(&world_position), "{:?}", local);
+                    assert_eq!(snapshot.get_block_light(&local), world.get_block_light(&world_position), "{:?}", local);
+                    assert_eq!(snapshot.get_sky_light(&local), world.get_sky_light(&world_position), "{:?}", local);
+                }
+            }
+        }
+
+        assert_eq!(snapshot.get_block(&Position::new(CHUNK_SIZE_X, CHUNK_SIZE_Y, CHUNK_SIZE_Z)), BLOCK_TYPE_AIR);
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::HashSet;
+use glam::Vec3;
+use crate::coords;
+use crate::world::Position;
+
+#[derive(Debug, Clone)]
+pub struct StreamingConfig {
+    // Distances are measured in chunks, horizontally as a circle and vertically as a band
+    pub render_distance: i64,
+    pub vertical_distance: i64,
+    pub unload_distance: i64,
+    pub max_requests_per_update: usize
+}
+
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        Self {
+            render_distance: 6,
+            vertical_distance: 3,
+            unload_distance: 8,
+            max_requests_per_update: 8
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct StreamingUpdate {
+    pub load: Vec<Position>,
+    pub unload: Vec<Position>
+}
+
+// Decides which chunks should be resident around the camera. The caller generates or loads the requested
+// chunks and reports them back with mark_loaded.
+#[derive(Debug)]
+pub struct ChunkStreamer {
+    config: StreamingConfig,
+    loaded: HashSet<Position>,
+    pending: HashSet<Position>
+}
+
+impl ChunkStreamer {
+    pub fn new(config: StreamingConfig) -> Self {
+        Self {
+            config,
+            loaded: HashSet::new(),
+            pending: HashSet::new()
+        }
+    }
+
+    pub fn config(&self) -> &StreamingConfig {
+        &self.config
+    }
+
+    pub fn loaded(&self) -> &HashSet<Position> {
+        &self.loaded
+    }
+
+    pub fn pending(&self) -> &HashSet<Position> {
+        &self.pending
+    }
+
+    pub fn camera_chunk(camera_position: Vec3) -> Position {
+        let block = Position::new(camera_position.x.floor() as i64, camera_position.y.floor() as i64, camera_position.z.floor() as i64);
+        coords::world_to_chunk(&block)
+    }
+
+    pub fn update(&mut self, camera_position: Vec3) -> StreamingUpdate {
+        let center = Self::camera_chunk(camera_position);
+        let mut update = StreamingUpdate::default();
+
+        let unload_distance = self.config.unload_distance;
+        let vertical_unload_distance = self.config.vertical_distance + unload_distance - self.config.render_distance;
+        let out_of_range = |position: &Position| !in_range(&center, position, unload_distance, vertical_unload_distance);
+
+        // Requests that left the area are reported too so their jobs can be cancelled, results that still
+        // arrive for them get rejected by mark_loaded
+        update.unload = self.loaded.iter()
+            .chain(self.pending.iter())
+            .filter(|position| out_of_range(position))
+            .copied()
+            .collect();
+        for position in update.unload.iter() {
+            self.loaded.remove(position);
+            self.pending.remove(position);
+        }
+
+        let render_distance = self.config.render_distance;
+        let vertical_distance = self.config.vertical_distance;
+        let mut missing = Vec::new();
+        for x in -render_distance..=render_distance {
+            for y in -vertical_distance..=vertical_distance {
+                for z in -render_distance..=render_distance {
+                    let position = center.offset(x, y, z);
+                    if in_range(&center, &position, render_distance, vertical_distance)
+                        && !self.loaded.contains(&position)
+                        && !self.pending.contains(&position) {
+                        missing.push(position);
+                    }
+                }
+            }
+        }
+
+        // Closest chunks first so the area around the camera fills in before the horizon
+        missing.sort_by_key(|position| (distance_squared(&center, position), position.y, position.x, position.z));
+        missing.truncate(self.config.max_requests_per_update);
+
+        for position in missing.iter() {
+            self.pending.insert(*position);
+        }
+        update.load = missing;
+
+        update
+    }
+
+    // Returns false when the chunk is no longer wanted and should be discarded
+    pub fn mark_loaded(&mut self, position: &Position) -> bool {
+        if self.pending.remove(position) {
+            self.loaded.insert(*position);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+fn distance_squared(a: &Position, b: &Position) -> i64 {
+    (a.x - b.x).pow(2) + (a.y - b.y).pow(2) + (a.z - b.z).pow(2)
+}
+
+fn in_range(center: &Position, position: &Position, horizontal: i64, vertical: i64) -> bool {
+    (position.x - center.x).pow(2) + (position.z - center.z).pow(2) <= horizontal * horizontal
+        && (position.y - center.y).abs() <= vertical
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> StreamingConfig {
+        StreamingConfig {
+            render_distance: 2,
+            vertical_distance: 1,
+            unload_distance: 3,
+            max_requests_per_update: 1000
+        }
+    }
+
+    fn load_everything(streamer: &mut ChunkStreamer, camera_position: Vec3) -> StreamingUpdate {
+        let update = streamer.update(camera_position);
+        for position in update.load.iter() {
+            assert!(streamer.mark_loaded(position));
+        }
+        update
+    }
+
+    #[test]
+    fn requests_chunks_within_render_distance() {
+        let mut streamer = ChunkStreamer::new(config());
+        let update = streamer.update(Vec3::new(8.0, 8.0, 8.0));
+
+        // 13 columns within a radius of 2, three chunks high
+        assert_eq!(update.load.len(), 13 * 3);
+        assert_eq!(update.load[0], Position::new(0, 0, 0));
+        assert_eq!(streamer.pending().len(), 13 * 3);
+        assert!(streamer.loaded().is_empty());
+
+        assert!(streamer.update(Vec3::new(8.0, 8.0, 8.0)).load.is_empty());
+    }
+
+    #[test]
+    fn limits_requests_per_update() {
+        let mut streamer = ChunkStreamer::new(StreamingConfig { max_requests_per_update: 5, ..config() });
+
+        let first = streamer.update(Vec3::ZERO);
+        let second = streamer.update(Vec3::ZERO);
+        assert_eq!(first.load.len(), 5);
+        assert_eq!(second.load.len(), 5);
+        assert!(first.load.iter().all(|position| !second.load.contains(position)));
+    }
+
+    #[test]
+    fn unloads_chunks_beyond_unload_distance() {
+        let mut streamer = ChunkStreamer::new(config());
+        load_everything(&mut streamer, Vec3::ZERO);
+        assert_eq!(streamer.loaded().len(), 13 * 3);
+
+        // Moving a single chunk keeps everything within the unload radius
+        let update = load_everything(&mut streamer, Vec3::new(16.0, 0.0, 0.0));
+        assert!(update.unload.is_empty());
+
+        let update = load_everything(&mut streamer, Vec3::new(-64.0, 0.0, 0.0));
+        assert!(update.unload.contains(&Position::new(2, 0, 0)));
+        assert!(!update.unload.contains(&Position::new(-1, 0, 0)));
+        assert!(streamer.loaded().iter().all(|position| position.x <= -1));
+    }
+
+    #[test]
+    fn rejects_results_for_abandoned_requests() {
+        let mut streamer = ChunkStreamer::new(config());
+        let update = streamer.update(Vec3::ZERO);
+        let moved = streamer.update(Vec3::new(1600.0, 0.0, 0.0));
+
+        assert!(moved.unload.contains(&update.load[0]));
+        assert!(!streamer.pending().contains(&update.load[0]));
+        assert!(!streamer.mark_loaded(&update.load[0]));
+        assert!(!streamer.loaded().contains(&update.load[0]));
+    }
+
+    #[test]
+    fn works_below_and_west_of_the_origin() {
+        let mut streamer = ChunkStreamer::new(config());
+        let update = streamer.update(Vec3::new(-0.5, -0.5, -0.5));
+
+        assert_eq!(update.load[0], Position::new(-1, -1, -1));
+    }
+}
@@ -0,0 +1,381 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use ron::extensions::Extensions;
+use serde::Deserialize;
+use crate::biome::BiomeMap;
+use crate::block::BlockRegistry;
+use crate::coords;
+use crate::light;
+use crate::random::Random;
+use crate::world::{Chunk, Position, World, BLOCK_TYPE_AIR, CHUNK_SIZE_X, CHUNK_SIZE_Y, CHUNK_SIZE_Z};
+use crate::worldgen::HeightmapGenerator;
+
+#[derive(Debug, Deserialize)]
+pub struct StructurePart {
+    pub from: (i64, i64, i64),
+    pub to: (i64, i64, i64),
+    pub block: String
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StructureDefinition {
+    pub name: String,
+    pub chance: f64,
+    pub parts: Vec<StructurePart>
+}
+
+#[derive(Debug, Clone)]
+pub struct StructureTemplate {
+    pub name: String,
+    pub chance: f64,
+    // Offsets from the block just above the ground the structure stands on
+    pub blocks: Vec<(Position, u16)>,
+    pub min: Position,
+    pub max: Position
+}
+
+impl StructureTemplate {
+    pub fn from_definition(definition: StructureDefinition, registry: &BlockRegistry) -> Result<Self, String> {
+        // Later parts overwrite earlier ones, and air parts cut blocks out of the template again
+        let mut blocks = BTreeMap::new();
+        for part in definition.parts {
+            let block = registry.id(&part.block).ok_or_else(|| format!("Unknown block {} in {}", part.block, definition.name))?;
+
+            for x in part.from.0.min(part.to.0)..=part.from.0.max(part.to.0) {
+                for y in part.from.1.min(part.to.1)..=part.from.1.max(part.to.1) {
+                    for z in part.from.2.min(part.to.2)..=part.from.2.max(part.to.2) {
+                        if block == BLOCK_TYPE_AIR {
+                            blocks.remove(&(x, y, z));
+                        } else {
+                            blocks.insert((x, y, z), block);
+                        }
+                    }
+                }
+            }
+        }
+
+        let blocks = blocks.into_iter()
+            .map(|((x, y, z), block)| (Position::new(x, y, z), block))
+            .collect::<Vec<_>>();
+
+        let mut min = Position::default();
+        let mut max = Position::default();
+        for (offset, _) in blocks.iter() {
+            min = Position::new(min.x.min(offset.x), min.y.min(offset.y), min.z.min(offset.z));
+            max = Position::new(max.x.max(offset.x), max.y.max(offset.y), max.z.max(offset.z));
+        }
+
+        Ok(Self {
+            name: definition.name,
+            chance: definition.chance,
+            blocks,
+            min,
+            max
+        })
+    }
+
+    // Blocks go in with their light, so emitters shine and leaves shade the ground right away
+    pub fn place_in_world(&self, world: &mut World, registry: &BlockRegistry, origin: &Position) {
+        for (offset, block) in self.blocks.iter() {
+            let position = origin.offset(offset.x, offset.y, offset.z);
+            if world.get_block(&position) == BLOCK_TYPE_AIR {
+                light::set_block(world, registry, &position, *block);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Placement {
+    pub template: usize,
+    pub origin: Position
+}
+
+#[derive(Debug, Clone)]
+pub struct StructureConfig {
+    pub attempts_per_chunk: u32
+}
+
+impl Default for StructureConfig {
+    fn default() -> Self {
+        Self {
+            attempts_per_chunk: 8
+        }
+    }
+}
+
+// Decorates generated chunks with structures. Placements are derived from the seed and the chunk column they
+// start in, and every chunk re-derives the placements of its neighbours, so a structure crossing a chunk edge
+// ends up identical no matter which of the chunks is generated first.
+pub struct StructurePlacer {
+    seed: u64,
+    config: StructureConfig,
+    templates: Vec<StructureTemplate>,
+    // Templates each biome picks from, by biome name
+    features: HashMap<String, Vec<usize>>,
+    reach: Position
+}
+
+impl StructurePlacer {
+    pub fn load(path: &str, registry: &BlockRegistry, biomes: &BiomeMap, seed: u32, config: StructureConfig) -> Self {
+        let source = fs::read_to_string(path).unwrap_or_else(|_| panic!("Failed to load {}", path));
+        Self::from_ron(&source, registry, biomes, seed, config).unwrap_or_else(|error| panic!("Failed to parse {}: {}", path, error))
+    }
+
+    pub fn from_ron(source: &str, registry: &BlockRegistry, biomes: &BiomeMap, seed: u32, config: StructureConfig) -> Result<Self, String> {
+        let definitions: Vec<StructureDefinition> = ron::Options::default()
+            .with_default_extension(Extensions::IMPLICIT_SOME)
+            .from_str(source)
+            .map_err(|error| error.to_string())?;
+
+        let templates = definitions.into_iter()
+            .map(|definition| StructureTemplate::from_definition(definition, registry))
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Self::new(seed, config, templates, biomes)
+    }
+
+    pub fn new(seed: u32, config: StructureConfig, templates: Vec<StructureTemplate>, biomes: &BiomeMap) -> Result<Self, String> {
+        let mut features = HashMap::new();
+        for biome in biomes.biomes() {
+            let indices = biome.features.iter()
+                .map(|feature| templates.iter().position(|template| &template.name == feature)
+                    .ok_or_else(|| format!("Unknown structure {} in {}", feature, biome.name)))
+                .collect::<Result<Vec<_>, String>>()?;
+            features.insert(biome.name.clone(), indices);
+        }
+
+        let mut reach = Position::default();
+        for template in templates.iter() {
+            let extent_x = template.min.x.abs().max(template.max.x.abs());
+            let extent_z = template.min.z.abs().max(template.max.z.abs());
+            reach.x = reach.x.max((extent_x + CHUNK_SIZE_X - 1) / CHUNK_SIZE_X);
+            reach.z = reach.z.max((extent_z + CHUNK_SIZE_Z - 1) / CHUNK_SIZE_Z);
+        }
+
+        Ok(Self {
+            seed: seed as u64,
+            config,
+            templates,
+            features,
+            reach
+        })
+    }
+
+    pub fn templates(&self) -> &[StructureTemplate] {
+        &self.templates
+    }
+
+    pub fn template(&self, name: &str) -> Option<&StructureTemplate> {
+        self.templates.iter().find(|template| template.name == name)
+    }
+
+    // Structures that start in the given chunk column
+    pub fn placements(&self, terrain: &HeightmapGenerator, chunk_x: i64, chunk_z: i64) -> Vec<Placement> {
+        let mut random = Random::at(self.seed, &Position::new(chunk_x, 0, chunk_z), 0x5747);
+        let mut placements = Vec::new();
+
+        for _ in 0..self.config.attempts_per_chunk {
+            let x = chunk_x * CHUNK_SIZE_X + random.next_below(CHUNK_SIZE_X as u64) as i64;
+            let z = chunk_z * CHUNK_SIZE_Z + random.next_below(CHUNK_SIZE_Z as u64) as i64;
+            let pick = random.next_u64();
+            let roll = random.next_f64();
+
+            let candidates = match terrain.biome_at(&Position::new(x, 0, z)) {
+                Some(biome) => self.features[&biome.name].clone(),
+                None => (0..self.templates.len()).collect()
+            };
+
+            if candidates.is_empty() {
+                continue;
+            }
+
+            let template = candidates[(pick % candidates.len() as u64) as usize];
+            if roll >= self.templates[template].chance {
+                continue;
+            }
+
+            let surface_height = terrain.surface_height(x, z);
+            if surface_height < terrain.config().sea_level {
+                continue;
+            }
+
+            // Caves may have carved the ground away, which is known without generating the chunk
+            let ground = Position::new(x, surface_height, z);
+            if terrain.terrain_block_at(&ground) != terrain.surface_layers(x, z).surface {
+                continue;
+            }
+
+            placements.push(Placement {
+                template,
+                origin: ground.offset(0, 1, 0)
+            });
+        }
+
+        placements
+    }
+
+    pub fn decorate(&self, chunk: &mut Chunk, terrain: &HeightmapGenerator) {
+        let chunk_position = *chunk.position();
+        let origin = coords::chunk_origin(&chunk_position);
+
+        for chunk_x in chunk_position.x - self.reach.x..=chunk_position.x + self.reach.x {
+            for chunk_z in chunk_position.z - self.reach.z..=chunk_position.z + self.reach.z {
+                for placement in self.placements(terrain, chunk_x, chunk_z) {
+                    let template = &self.templates[placement.template];
+                    if placement.origin.y + template.max.y < origin.y || placement.origin.y + template.min.y >= origin.y + CHUNK_SIZE_Y {
+                        continue;
+                    }
+
+                    for (offset, block) in template.blocks.iter() {
+                        let position = placement.origin.offset(offset.x, offset.y, offset.z);
+                        let (block_chunk, local_position) = coords::split(&position);
+
+                        if block_chunk == chunk_position && chunk.get_block(&local_position) == BLOCK_TYPE_AIR {
+                            chunk.set_block(*block, &local_position);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::biome::{BiomeConfig, BiomeMap};
+    use crate::worldgen::{TerrainBlocks, TerrainConfig, WorldGenerator};
+
+    fn biomes(registry: &BlockRegistry, seed: u32) -> BiomeMap {
+        BiomeMap::load("biomes.ron", registry, seed, BiomeConfig::default())
+    }
+
+    fn blocks_of(chunk: &Chunk) -> Vec<u16> {
+        let mut blocks = Vec::new();
+        for x in 0..CHUNK_SIZE_X {
+            for y in 0..CHUNK_SIZE_Y {
+                for z in 0..CHUNK_SIZE_Z {
+                    blocks.push(chunk.get_block(&Position::new(x, y, z)));
+                }
+            }
+        }
+        blocks
+    }
+
+    #[test]
+    fn air_parts_cut_templates() {
+        let registry = BlockRegistry::load("blocks.ron");
+        let placer = StructurePlacer::load("structures.ron", &registry, &biomes(&registry, 0), 0, StructureConfig::default());
+        let tree = placer.template("oak_tree").unwrap();
+
+        let log = registry.id("log").unwrap();
+        let block_at = |x, y, z| tree.blocks.iter().find(|(offset, _)| *offset == Position::new(x, y, z)).map(|(_, block)| *block);
+
+        assert_eq!(block_at(0, 4, 0), Some(log));
+        assert_eq!(block_at(2, 3, 2), registry.id("leaves"));
+        assert_eq!(block_at(2, 4, 2), None);
+        assert_eq!(tree.min, Position::new(-2, 0, -2));
+        assert_eq!(tree.max, Position::new(2, 6, 2));
+    }
+
+    #[test]
+    fn placed_structures_are_lit() {
+        let registry = BlockRegistry::load("blocks.ron");
+        let mut world = World::with_registry(&registry);
+        world.add_chunk(Chunk::new(Position::default(), BLOCK_TYPE_AIR));
+        light::light_chunk(&mut world, &registry, &Position::default());
+
+        let lamp = StructureTemplate::from_definition(StructureDefinition {
+            name: "lamp".to_owned(),
+            chance: 1.0,
+            parts: vec![
+                StructurePart { from: (0, 0, 0), to: (0, 2, 0), block: "log".to_owned() },
+                StructurePart { from: (0, 3, 0), to: (0, 3, 0), block: "torch".to_owned() },
+                StructurePart { from: (-1, 4, -1), to: (1, 4, 1), block: "leaves".to_owned() }
+            ]
+        }, &registry).unwrap();
+        lamp.place_in_world(&mut world, &registry, &Position::new(8, 2, 8));
+
+        let torch = registry.light_emission(registry.id("torch").unwrap());
+        assert_eq!(world.get_block_light(&Position::new(8, 5, 8)), torch);
+        assert_eq!(world.get_block_light(&Position::new(9, 5, 8)), torch - 1);
+        // The log shades the block below it from the sky
+        assert_eq!(world.get_sky_light(&Position::new(9, 1, 8)), light::MAX_LIGHT);
+        assert_eq!(world.get_sky_light(&Position::new(8, 1, 8)), light::MAX_LIGHT - 1);
+    }
+
+    #[test]
+    fn unknown_biome_features_are_rejected() {
+        let registry = BlockRegistry::load("blocks.ron");
+        let biomes = BiomeMap::from_ron(r#"[
+            (
+                name: "swamp",
+                temperature: 0.0,
+                humidity: 0.5,
+                surface_block: "grass",
+                filler_block: "dirt",
+                filler_depth: 3,
+                base_height: 6.0,
+                amplitude: 2.0,
+                features: ["oak_tree", "willow_tree"]
+            )
+        ]"#, &registry, 0, BiomeConfig::default()).unwrap();
+        let source = fs::read_to_string("structures.ron").unwrap();
+
+        let error = StructurePlacer::from_ron(&source, &registry, &biomes, 0, StructureConfig::default()).err().unwrap();
+        assert_eq!(error, "Unknown structure willow_tree in swamp");
+    }
+
+    #[test]
+    fn structures_continue_into_neighbouring_chunks() {
+        let seed = 21;
+        let registry = BlockRegistry::load("blocks.ron");
+        let terrain = || HeightmapGenerator::new(seed, TerrainConfig::default(), TerrainBlocks::from_registry(&registry))
+            .with_biomes(biomes(&registry, seed));
+        let generator = || terrain()
+            .with_structures(StructurePlacer::load("structures.ron", &registry, &biomes(&registry, seed), seed, StructureConfig::default()));
+        let placer = StructurePlacer::load("structures.ron", &registry, &biomes(&registry, seed), seed, StructureConfig::default());
+        let plain_terrain = terrain();
+        let first_generator = generator();
+
+        let mut chunks = std::collections::HashMap::new();
+        let mut crossing = std::collections::HashSet::new();
+
+        for chunk_x in -3..3 {
+            for chunk_z in -3..3 {
+                for placement in placer.placements(&plain_terrain, chunk_x, chunk_z) {
+                    let template = &placer.templates()[placement.template];
+                    let origin_chunk = coords::world_to_chunk(&placement.origin);
+
+                    for (offset, _) in template.blocks.iter() {
+                        let position = placement.origin.offset(offset.x, offset.y, offset.z);
+                        let (chunk_position, local_position) = coords::split(&position);
+                        let chunk = chunks.entry(chunk_position)
+                            .or_insert_with(|| first_generator.generate_chunk(&chunk_position));
+
+                        assert_ne!(chunk.get_block(&local_position), BLOCK_TYPE_AIR, "{} at {:?}", template.name, position);
+                        if chunk_position != origin_chunk {
+                            crossing.insert((origin_chunk, chunk_position));
+                        }
+                    }
+                }
+            }
+        }
+        assert!(!crossing.is_empty());
+
+        // Whichever of the two chunks a structure spans is generated first, both come out the same
+        for (a, b) in crossing {
+            let forward = generator();
+            let forward_a = forward.generate_chunk(&a);
+            let forward_b = forward.generate_chunk(&b);
+
+            let backward = generator();
+            let backward_b = backward.generate_chunk(&b);
+            let backward_a = backward.generate_chunk(&a);
+
+            assert_eq!(blocks_of(&forward_a), blocks_of(&backward_a), "{:?} after {:?}", a, b);
+            assert_eq!(blocks_of(&forward_b), blocks_of(&backward_b), "{:?} after {:?}", b, a);
+        }
+    }
+}
@@ -1,6 +1,8 @@
-use std::num::NonZeroU32;
-use wgpu::{BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingType, Device, Extent3d, Queue, ShaderStages, Texture, TextureAspect, TextureDescriptor, TextureDimension, TextureFormat, TextureSampleType, TextureUsages, TextureView, TextureViewDescriptor, TextureViewDimension};
+use std::num::{NonZeroU32, NonZeroU8};
+use wgpu::{AddressMode, Device, Extent3d, FilterMode, Queue, Sampler, SamplerDescriptor, Texture, TextureAspect, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages, TextureView, TextureViewDescriptor, TextureViewDimension};
+use image::RgbaImage;
 use wgpu::util::DeviceExt;
+use crate::mipmap::{self, MipConfig};
 
 pub struct Texture2D {
     pub texture: Texture,
@@ -9,18 +11,24 @@ pub struct Texture2D {
 
 impl Texture2D {
     pub fn new(device: &Device, queue: &Queue, path: &str) -> Self {
-        let image = image::open(path).expect(&format!("Failed to load {}", path))
+        let image = image::open(path).unwrap_or_else(|_| panic!("Failed to load {}", path))
             .to_rgba8();
+        Self::from_image(device, queue, &image, &MipConfig::default())
+    }
+
+    pub fn from_image(device: &Device, queue: &Queue, image: &RgbaImage, mips: &MipConfig) -> Self {
+        let levels = mipmap::generate(image, mips);
+        let data = levels.iter().flat_map(|level| level.as_raw().iter().copied()).collect::<Vec<_>>();
 
         let texture = device.create_texture_with_data(queue, &TextureDescriptor {
             label: None,
             size: Extent3d { width: image.width(), height: image.height(), depth_or_array_layers: 1 },
-            mip_level_count: 1,
+            mip_level_count: levels.len() as u32,
             sample_count: 1,
             dimension: TextureDimension::D2,
             format: TextureFormat::Rgba8UnormSrgb,
             usage: TextureUsages::TEXTURE_BINDING,
-        }, &image);
+        }, &data);
 
         let view= texture.create_view(&TextureViewDescriptor {
             label: None,
@@ -28,7 +36,7 @@ impl Texture2D {
             dimension: Some(TextureViewDimension::D2),
             aspect: TextureAspect::All,
             base_mip_level: 0,
-            mip_level_count: NonZeroU32::new(1),
+            mip_level_count: NonZeroU32::new(levels.len() as u32),
             base_array_layer: 0,
             array_layer_count: NonZeroU32::new(1),
         });
@@ -38,4 +46,99 @@ impl Texture2D {
             view
         }
     }
-}
\ No newline at end of file
+}
+
+// Same sized images stacked into the layers of one texture, sampled with a layer index
+pub struct Texture2DArray {
+    pub texture: Texture,
+    pub view: TextureView,
+    pub layer_count: u32
+}
+
+impl Texture2DArray {
+    pub fn new(device: &Device, queue: &Queue, images: &[RgbaImage], mips: &MipConfig) -> Self {
+        let (width, height) = images.first().expect("Texture arrays need at least one layer").dimensions();
+        assert!(images.iter().all(|image| image.dimensions() == (width, height)), "Texture array layers differ in size");
+
+        // Every layer brings its own mip chain, so cutout layers can keep their coverage next to blended ones.
+        // The data holds the layers one after the other, each with all of its levels.
+        let level_count = mipmap::level_count(width, height).min(mips.max_levels.max(1));
+        let data = images.iter()
+            .flat_map(|image| mipmap::generate(image, mips))
+            .flat_map(|level| level.into_raw())
+            .collect::<Vec<_>>();
+        let layer_count = images.len() as u32;
+
+        let texture = device.create_texture_with_data(queue, &TextureDescriptor {
+            label: None,
+            size: Extent3d { width, height, depth_or_array_layers: layer_count },
+            mip_level_count: level_count,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::Rgba8UnormSrgb,
+            usage: TextureUsages::TEXTURE_BINDING,
+        }, &data);
+
+        let view = texture.create_view(&TextureViewDescriptor {
+            label: None,
+            format: Some(TextureFormat::Rgba8UnormSrgb),
+            dimension: Some(TextureViewDimension::D2Array),
+            aspect: TextureAspect::All,
+            base_mip_level: 0,
+            mip_level_count: NonZeroU32::new(level_count),
+            base_array_layer: 0,
+            array_layer_count: NonZeroU32::new(layer_count),
+        });
+
+        Self {
+            texture,
+            view,
+            layer_count
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct SamplerConfig {
+    pub address_mode: AddressMode,
+    // Nearest keeps block textures crisp up close
+    pub mag_filter: FilterMode,
+    pub min_filter: FilterMode,
+    pub mipmap_filter: FilterMode,
+    // Samples along the direction a texture is stretched in, 1, 2, 4, 8 or 16. Ignored where unsupported, and
+    // only allowed when every filter is linear.
+    pub anisotropy: Option<NonZeroU8>
+}
+
+impl Default for SamplerConfig {
+    fn default() -> Self {
+        Self {
+            address_mode: AddressMode::Repeat,
+            mag_filter: FilterMode::Nearest,
+            min_filter: FilterMode::Linear,
+            mipmap_filter: FilterMode::Linear,
+            anisotropy: None
+        }
+    }
+}
+
+impl SamplerConfig {
+    pub fn create(&self, device: &Device) -> Sampler {
+        let linear = [self.mag_filter, self.min_filter, self.mipmap_filter].iter().all(|filter| *filter == FilterMode::Linear);
+
+        device.create_sampler(&SamplerDescriptor {
+            label: None,
+            address_mode_u: self.address_mode,
+            address_mode_v: self.address_mode,
+            address_mode_w: self.address_mode,
+            mag_filter: self.mag_filter,
+            min_filter: self.min_filter,
+            mipmap_filter: self.mipmap_filter,
+            lod_min_clamp: 0.0,
+            lod_max_clamp: f32::MAX,
+            compare: None,
+            anisotropy_clamp: self.anisotropy.filter(|_| linear),
+            border_color: None,
+        })
+    }
+}
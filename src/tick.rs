@@ -0,0 +1,508 @@
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+use crate::block::{BlockRegistry, Face};
+use crate::coords;
+use crate::light;
+use crate::random::Random;
+use crate::world::{Position, World, CHUNK_SIZE_X, CHUNK_SIZE_Y, CHUNK_SIZE_Z};
+
+// How one type of block reacts to the passing of time. Handlers change the world through the context, so every
+// block they set wakes up its neighbours in turn.
+pub trait TickHandler {
+    // An update scheduled for the position came due and the block there has this handler
+    fn scheduled_tick(&self, _context: &mut TickContext, _position: &Position, _block: u16) {}
+
+    // Picked at random among the blocks of a loaded chunk, for slow changes like growth
+    fn random_tick(&self, _context: &mut TickContext, _position: &Position, _block: u16) {}
+
+    // The block itself or one of its six neighbours was just set
+    fn neighbour_changed(&self, _context: &mut TickContext, _position: &Position, _block: u16) {}
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct TickConfig {
+    pub ticks_per_second: f64,
+    // Blocks of every loaded chunk that get a random tick each tick
+    pub random_ticks_per_chunk: u32
+}
+
+impl Default for TickConfig {
+    fn default() -> Self {
+        Self {
+            ticks_per_second: 20.0,
+            random_ticks_per_chunk: 3
+        }
+    }
+}
+
+// Positions waiting for a scheduled update, by the tick they are due on. A position is only queued once, for
+// the earliest tick anyone asked for. Updates in chunks that are not loaded wait with them, by chunk, along with
+// the ticks they had left.
+#[derive(Default)]
+struct TickQueue {
+    scheduled: BTreeMap<u64, Vec<Position>>,
+    pending: HashMap<Position, u64>,
+    unloaded: HashMap<Position, Vec<(Position, u64)>>,
+    tick: u64
+}
+
+impl TickQueue {
+    fn schedule(&mut self, position: &Position, delay: u64) {
+        let tick = self.tick + delay.max(1);
+        if self.pending.get(position).is_some_and(|pending| *pending <= tick) {
+            return;
+        }
+
+        self.pending.insert(*position, tick);
+        self.scheduled.entry(tick).or_default().push(*position);
+    }
+
+    // Positions of the earliest due tick in the order they were scheduled
+    fn pop_due(&mut self) -> Option<Vec<Position>> {
+        let entry = self.scheduled.first_entry().filter(|entry| *entry.key() <= self.tick)?;
+        let tick = *entry.key();
+        let mut positions = entry.remove();
+
+        // Positions scheduled again for an earlier tick already ran
+        positions.retain(|position| {
+            let due = self.pending.get(position) == Some(&tick);
+            if due {
+                self.pending.remove(position);
+            }
+            due
+        });
+        Some(positions)
+    }
+
+    fn unload(&mut self, chunk_position: &Position) {
+        let tick = self.tick;
+        let positions = self.pending.iter()
+            .filter(|(position, _)| coords::world_to_chunk(position) == *chunk_position)
+            .map(|(position, due)| (*position, due.saturating_sub(tick)))
+            .collect::<Vec<_>>();
+
+        // Their entries in the schedule are skipped once they are no longer pending
+        for (position, delay) in positions {
+            self.pending.remove(&position);
+            self.wait_for_chunk(&position, delay);
+        }
+    }
+
+    fn wait_for_chunk(&mut self, position: &Position, delay: u64) {
+        self.unloaded.entry(coords::world_to_chunk(position)).or_default().push((*position, delay));
+    }
+
+    fn reload(&mut self, chunk_position: &Position) {
+        for (position, delay) in self.unloaded.remove(chunk_position).unwrap_or_default() {
+            self.schedule(&position, delay);
+        }
+    }
+}
+
+// What a handler gets to see and change while it runs
+pub struct TickContext<'a> {
+    world: &'a mut World,
+    registry: &'a BlockRegistry,
+    handlers: &'a HashMap<u16, Rc<dyn TickHandler>>,
+    queue: &'a mut TickQueue,
+    random: &'a mut Random
+}
+
+impl<'a> TickContext<'a> {
+    pub fn world(&self) -> &World {
+        self.world
+    }
+
+    pub fn registry(&self) -> &'a BlockRegistry {
+        self.registry
+    }
+
+    pub fn current_tick(&self) -> u64 {
+        self.queue.tick
+    }
+
+    pub fn random(&mut self) -> &mut Random {
+        self.random
+    }
+
+    pub fn get_block(&self, position: &Position) -> u16 {
+        self.world.get_block(position)
+    }
+
+    pub fn is_loaded(&self, position: &Position) -> bool {
+        self.world.chunks.contains_key(&coords::world_to_chunk(position))
+    }
+
+    // Runs the handler of whatever block is at the position in the given number of ticks, at least one
+    pub fn schedule(&mut self, position: &Position, delay: u64) {
+        self.queue.schedule(position, delay);
+    }
+
+    // Sets a block with its light and tells it and its neighbours. Chunks that are not loaded are left alone.
+    pub fn set_block(&mut self, position: &Position, block: u16) {
+        if !self.is_loaded(position) || self.world.get_block(position) == block {
+            return;
+        }
+
+        light::set_block(self.world, self.registry, position, block);
+
+        let handlers = self.handlers;
+        let neighbours = Face::ALL.map(|face| {
+            let normal = face.normal();
+            position.offset(normal.x, normal.y, normal.z)
+        });
+        for neighbour in [*position].iter().chain(neighbours.iter()) {
+            let block = self.world.get_block(neighbour);
+            if let Some(handler) = handlers.get(&block) {
+                handler.neighbour_changed(self, neighbour, block);
+            }
+        }
+    }
+}
+
+// Advances the world at a fixed rate. Every tick runs the scheduled updates that came due, in the order they
+// were scheduled, and then the random ticks of the loaded chunks from a seeded generator, so the same world and
+// edits always play out the same way.
+pub struct TickScheduler {
+    config: TickConfig,
+    handlers: HashMap<u16, Rc<dyn TickHandler>>,
+    queue: TickQueue,
+    random: Random
+}
+
+impl TickScheduler {
+    pub fn new(seed: u64, config: TickConfig) -> Self {
+        Self {
+            config,
+            handlers: HashMap::new(),
+            queue: TickQueue::default(),
+            random: Random::new(seed)
+        }
+    }
+
+    pub fn config(&self) -> &TickConfig {
+        &self.config
+    }
+
+    // One handler can serve several block types, like all levels of a fluid
+    pub fn register(&mut self, blocks: impl IntoIterator<Item = u16>, handler: impl TickHandler + 'static) {
+        let handler: Rc<dyn TickHandler> = Rc::new(handler);
+        for block in blocks {
+            self.handlers.insert(block, handler.clone());
+        }
+    }
+
+    pub fn current_tick(&self) -> u64 {
+        self.queue.tick
+    }
+
+    // No scheduled updates are waiting in loaded chunks, random ticks may still change the world
+    pub fn is_idle(&self) -> bool {
+        self.queue.pending.is_empty()
+    }
+
+    pub fn schedule(&mut self, position: &Position, delay: u64) {
+        self.queue.schedule(position, delay);
+    }
+
+    // Edits from outside the simulation, like the player's, go through here so the blocks around them react
+    pub fn set_block(&mut self, world: &mut World, registry: &BlockRegistry, position: &Position, block: u16) {
+        self.context(world, registry).set_block(position, block);
+    }
+
+    // Keeps the updates waiting in a chunk that is about to be unloaded until it is loaded again
+    pub fn chunk_unloaded(&mut self, chunk_position: &Position) {
+        self.queue.unload(chunk_position);
+    }
+
+    // Call once a chunk was added and lit. Its waiting updates are queued again, and the blocks on both sides of
+    // every face it shares with a loaded chunk are told, since they could not see past it while it was missing.
+    pub fn chunk_loaded(&mut self, world: &mut World, registry: &BlockRegistry, chunk_position: &Position) {
+        self.queue.reload(chunk_position);
+
+        let origin = coords::local_to_world(chunk_position, &Position::default());
+        let mut context = self.context(world, registry);
+        let handlers = context.handlers;
+        for face in Face::ALL {
+            let normal = face.normal();
+            if !context.world.chunks.contains_key(&chunk_position.offset(normal.x, normal.y, normal.z)) {
+                continue;
+            }
+
+            // The layer of this chunk against the face, then the layer of the neighbour against it
+            for side in [0, 1] {
+                let layer = |normal: i64, size: i64| match normal {
+                    1 => size - 1 + side..=size - 1 + side,
+                    -1 => -side..=-side,
+                    _ => 0..=size - 1
+                };
+
+                for x in layer(normal.x, CHUNK_SIZE_X) {
+                    for y in layer(normal.y, CHUNK_SIZE_Y) {
+                        for z in layer(normal.z, CHUNK_SIZE_Z) {
+                            let position = origin.offset(x, y, z);
+                            let block = context.get_block(&position);
+                            if let Some(handler) = handlers.get(&block) {
+                                handler.neighbour_changed(&mut context, &position, block);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn tick(&mut self, world: &mut World, registry: &BlockRegistry) {
+        self.queue.tick += 1;
+        let random_ticks = self.config.random_ticks_per_chunk;
+        let mut context = self.context(world, registry);
+        let handlers = context.handlers;
+
+        while let Some(positions) = context.queue.pop_due() {
+            for position in positions {
+                // Updates scheduled into chunks that are not loaded run once they are
+                if !context.is_loaded(&position) {
+                    context.queue.wait_for_chunk(&position, 1);
+                    continue;
+                }
+
+                let block = context.get_block(&position);
+                if let Some(handler) = handlers.get(&block) {
+                    handler.scheduled_tick(&mut context, &position, block);
+                }
+            }
+        }
+
+        let mut chunks = context.world.chunks.keys().copied().collect::<Vec<_>>();
+        chunks.sort_by_key(|position| (position.x, position.y, position.z));
+        for chunk_position in chunks {
+            // Chunks can be filled with a single block that has nothing to do
+            let Some(chunk) = context.world.chunks.get(&chunk_position) else {
+                continue;
+            };
+            if chunk.is_uniform() && !handlers.contains_key(&chunk.get_block(&Position::default())) {
+                continue;
+            }
+
+            for _ in 0..random_ticks {
+                let local_position = Position::new(
+                    context.random.next_below(CHUNK_SIZE_X as u64) as i64,
+                    context.random.next_below(CHUNK_SIZE_Y as u64) as i64,
+                    context.random.next_below(CHUNK_SIZE_Z as u64) as i64
+                );
+                let position = coords::local_to_world(&chunk_position, &local_position);
+                let block = context.get_block(&position);
+                if let Some(handler) = handlers.get(&block) {
+                    handler.random_tick(&mut context, &position, block);
+                }
+            }
+        }
+    }
+
+    fn context<'a>(&'a mut self, world: &'a mut World, registry: &'a BlockRegistry) -> TickContext<'a> {
+        TickContext {
+            world,
+            registry,
+            handlers: &self.handlers,
+            queue: &mut self.queue,
+            random: &mut self.random
+        }
+    }
+}
+
+// Fixtures for the tests of the tick handlers in other modules
+#[cfg(test)]
+pub(crate) mod test_support {
+    use super::*;
+    use crate::world::{Chunk, BLOCK_TYPE_AIR};
+
+    // A floor of the given block at y = -1 under open sky, three by three chunks around the origin
+    pub fn floor_world(registry: &BlockRegistry, floor: u16) -> World {
+        let mut world = World::with_registry(registry);
+        for x in -1..=1 {
+            for z in -1..=1 {
+                world.add_chunk(Chunk::new(Position::new(x, -1, z), floor));
+                world.add_chunk(Chunk::new(Position::new(x, 0, z), BLOCK_TYPE_AIR));
+                light::light_chunk(&mut world, registry, &Position::new(x, -1, z));
+                light::light_chunk(&mut world, registry, &Position::new(x, 0, z));
+            }
+        }
+        world
+    }
+
+    pub fn run(world: &mut World, registry: &BlockRegistry, scheduler: &mut TickScheduler, ticks: u64) {
+        for _ in 0..ticks {
+            scheduler.tick(world, registry);
+        }
+    }
+
+    // Ticks until the world is done or the budget runs out, true when it got done
+    pub fn run_until(world: &mut World, registry: &BlockRegistry, scheduler: &mut TickScheduler, budget: u64, mut done: impl FnMut(&World) -> bool) -> bool {
+        for _ in 0..budget {
+            if done(world) {
+                return true;
+            }
+            scheduler.tick(world, registry);
+        }
+        done(world)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use super::*;
+    use crate::world::{Chunk, BLOCK_TYPE_AIR};
+
+    // Kind of call, position and tick
+    type Call = (&'static str, Position, u64);
+
+    // Writes down every call it gets
+    #[derive(Clone, Default)]
+    struct Recorder {
+        calls: Rc<RefCell<Vec<Call>>>
+    }
+
+    impl TickHandler for Recorder {
+        fn scheduled_tick(&self, context: &mut TickContext, position: &Position, _block: u16) {
+            self.calls.borrow_mut().push(("scheduled", *position, context.current_tick()));
+        }
+
+        fn random_tick(&self, context: &mut TickContext, position: &Position, _block: u16) {
+            self.calls.borrow_mut().push(("random", *position, context.current_tick()));
+        }
+
+        fn neighbour_changed(&self, context: &mut TickContext, position: &Position, _block: u16) {
+            self.calls.borrow_mut().push(("neighbour", *position, context.current_tick()));
+        }
+    }
+
+    fn setup() -> (World, BlockRegistry) {
+        let registry = BlockRegistry::load("blocks.ron");
+        let mut world = World::with_registry(&registry);
+        world.add_chunk(Chunk::new(Position::default(), BLOCK_TYPE_AIR));
+        light::light_chunk(&mut world, &registry, &Position::default());
+        (world, registry)
+    }
+
+    #[test]
+    fn scheduled_updates_run_in_order_once() {
+        let (mut world, registry) = setup();
+        let sand = registry.id("sand").unwrap();
+        let recorder = Recorder::default();
+        let mut scheduler = TickScheduler::new(1, TickConfig { random_ticks_per_chunk: 0, ..TickConfig::default() });
+        scheduler.register([sand], recorder.clone());
+
+        for x in 0..3 {
+            world.set_block(&Position::new(x, 0, 0), sand);
+        }
+        scheduler.schedule(&Position::new(2, 0, 0), 3);
+        scheduler.schedule(&Position::new(0, 0, 0), 3);
+        scheduler.schedule(&Position::new(1, 0, 0), 2);
+        // Asking again for a later tick keeps the earlier one, asking for an earlier one moves it
+        scheduler.schedule(&Position::new(1, 0, 0), 5);
+        scheduler.schedule(&Position::new(2, 0, 0), 1);
+        // Nothing happens where the block has no handler
+        scheduler.schedule(&Position::new(5, 0, 0), 1);
+
+        for _ in 0..10 {
+            scheduler.tick(&mut world, &registry);
+        }
+
+        assert!(scheduler.is_idle());
+        assert_eq!(*recorder.calls.borrow(), [
+            ("scheduled", Position::new(2, 0, 0), 1),
+            ("scheduled", Position::new(1, 0, 0), 2),
+            ("scheduled", Position::new(0, 0, 0), 3)
+        ]);
+    }
+
+    #[test]
+    fn setting_a_block_notifies_it_and_its_neighbours() {
+        let (mut world, registry) = setup();
+        let sand = registry.id("sand").unwrap();
+        let stone = registry.id("stone").unwrap();
+        let recorder = Recorder::default();
+        let mut scheduler = TickScheduler::new(1, TickConfig::default());
+        scheduler.register([sand], recorder.clone());
+
+        world.set_block(&Position::new(4, 5, 4), sand);
+        world.set_block(&Position::new(4, 4, 5), sand);
+        scheduler.set_block(&mut world, &registry, &Position::new(4, 4, 4), sand);
+        scheduler.set_block(&mut world, &registry, &Position::new(4, 4, 4), sand);
+        scheduler.set_block(&mut world, &registry, &Position::new(30, 4, 4), stone);
+
+        let calls = recorder.calls.borrow();
+        assert_eq!(*calls, [
+            ("neighbour", Position::new(4, 4, 4), 0),
+            ("neighbour", Position::new(4, 5, 4), 0),
+            ("neighbour", Position::new(4, 4, 5), 0)
+        ]);
+        assert_eq!(world.get_block(&Position::new(30, 4, 4)), BLOCK_TYPE_AIR);
+    }
+
+    #[test]
+    fn random_ticks_stay_in_loaded_chunks_and_repeat_with_the_seed() {
+        let run = |seed| {
+            let (mut world, registry) = setup();
+            let stone = registry.id("stone").unwrap();
+            world.add_chunk(Chunk::new(Position::new(1, 0, 0), stone));
+            world.add_chunk(Chunk::new(Position::new(-1, 0, 0), registry.id("dirt").unwrap()));
+
+            let recorder = Recorder::default();
+            let mut scheduler = TickScheduler::new(seed, TickConfig::default());
+            scheduler.register([stone], recorder.clone());
+            for _ in 0..20 {
+                scheduler.tick(&mut world, &registry);
+            }
+            recorder.calls.take()
+        };
+
+        let calls = run(7);
+        assert_eq!(calls.len(), 20 * TickConfig::default().random_ticks_per_chunk as usize);
+        assert!(calls.iter().all(|(kind, position, _)| *kind == "random" && coords::world_to_chunk(position) == Position::new(1, 0, 0)));
+        assert_eq!(calls, run(7));
+        assert_ne!(calls, run(8));
+    }
+
+    #[test]
+    fn updates_wait_for_unloaded_chunks_and_loading_wakes_the_border() {
+        let (mut world, registry) = setup();
+        let sand = registry.id("sand").unwrap();
+        let recorder = Recorder::default();
+        let mut scheduler = TickScheduler::new(1, TickConfig { random_ticks_per_chunk: 0, ..TickConfig::default() });
+        scheduler.register([sand], recorder.clone());
+
+        let chunk_position = Position::new(1, 0, 0);
+        world.add_chunk(Chunk::new(chunk_position, BLOCK_TYPE_AIR));
+        for x in 15..18 {
+            world.set_block(&Position::new(x, 0, 0), sand);
+        }
+
+        // One update is queued before the chunk goes, one is asked for while it is gone
+        scheduler.schedule(&Position::new(16, 0, 0), 3);
+        scheduler.chunk_unloaded(&chunk_position);
+        let chunk = world.remove_chunk(&chunk_position).unwrap();
+        scheduler.schedule(&Position::new(17, 0, 0), 1);
+        for _ in 0..10 {
+            scheduler.tick(&mut world, &registry);
+        }
+        assert!(scheduler.is_idle());
+        assert!(recorder.calls.borrow().is_empty());
+
+        world.add_chunk(chunk);
+        light::light_chunk(&mut world, &registry, &chunk_position);
+        scheduler.chunk_loaded(&mut world, &registry, &chunk_position);
+        for _ in 0..10 {
+            scheduler.tick(&mut world, &registry);
+        }
+
+        assert!(scheduler.is_idle());
+        assert_eq!(*recorder.calls.borrow(), [
+            ("neighbour", Position::new(16, 0, 0), 10),
+            ("neighbour", Position::new(15, 0, 0), 10),
+            ("scheduled", Position::new(17, 0, 0), 11),
+            ("scheduled", Position::new(16, 0, 0), 13)
+        ]);
+    }
+}
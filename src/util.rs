@@ -1,24 +1,91 @@
-use glam::{Mat4, Vec2, Vec3};
+use glam::{Mat4, Vec2, Vec3, Vec4};
 use std::{mem, slice};
 use wgpu::util::{BufferInitDescriptor, DeviceExt};
-use wgpu::{Buffer, BufferDescriptor, BufferUsages, Device};
+use wgpu::{Buffer, BufferUsages, Device};
 
 #[repr(C)]
 pub struct VSInput {
     position: Vec3,
-    uv: Vec2
+    uv: Vec2,
+    // Ambient occlusion, 1 is unoccluded
+    ao: f32,
+    // Block light and skylight, 1 is the brightest level
+    light: f32,
+    sky_light: f32,
+    // Atlas rectangle the texture coordinates wrap around in, the minimum in xy and the size in zw
+    tile: Vec4,
+    // Layer of the block texture array
+    layer: u32
 }
 
 impl VSInput {
     pub fn new(position: Vec3, uv: Vec2) -> Self {
         Self {
             position,
-            uv
+            uv,
+            ao: 1.0,
+            light: 0.0,
+            sky_light: 0.0,
+            tile: Vec4::new(0.0, 0.0, 1.0, 1.0),
+            layer: 0
         }
     }
+
+    pub fn with_ao(mut self, ao: f32) -> Self {
+        self.ao = ao;
+        self
+    }
+
+    pub fn with_light(mut self, light: f32) -> Self {
+        self.light = light;
+        self
+    }
+
+    pub fn with_sky_light(mut self, sky_light: f32) -> Self {
+        self.sky_light = sky_light;
+        self
+    }
+
+    pub fn with_tile(mut self, tile: Vec4) -> Self {
+        self.tile = tile;
+        self
+    }
+
+    pub fn with_layer(mut self, layer: u32) -> Self {
+        self.layer = layer;
+        self
+    }
+
+    pub fn position(&self) -> Vec3 {
+        self.position
+    }
+
+    pub fn uv(&self) -> Vec2 {
+        self.uv
+    }
+
+    pub fn ao(&self) -> f32 {
+        self.ao
+    }
+
+    pub fn light(&self) -> f32 {
+        self.light
+    }
+
+    pub fn sky_light(&self) -> f32 {
+        self.sky_light
+    }
+
+    pub fn tile(&self) -> Vec4 {
+        self.tile
+    }
+
+    pub fn layer(&self) -> u32 {
+        self.layer
+    }
 }
 
-pub fn create_uniform_buffer(device: &Device, size: usize) -> Buffer {
+pub fn create_uniform_buffer(device: &Device, _size: usize) -> Buffer {
     let transform = Mat4::from_scale(Vec3::new(0.5, 0.5, 0.5));
 
     device.create_buffer_init(&BufferInitDescriptor {
@@ -34,10 +101,10 @@ pub fn create_uniform_buffer(device: &Device, size: usize) -> Buffer {
 }
 
 pub fn create_vertex_buffer(device: &Device) -> Buffer {
-    let vertices = [VSInput { position: Vec3::new(-0.8, -0.5, -1.0), uv: Vec2::new(0., 1.) },
-                            VSInput { position: Vec3::new(0.5, -0.5, -1.0), uv: Vec2::new(1., 1.) },
-                             VSInput { position: Vec3::new(-0.8, 0.5, -1.0), uv: Vec2::new(0., 0.,) },
-                             VSInput { position: Vec3::new(0.5, 0.5, -1.0), uv: Vec2::new(1., 0.) }];
+    let vertices = [VSInput { position: Vec3::new(-0.8, -0.5, -1.0), uv: Vec2::new(0., 1.), ao: 1.0, light: 0.0, sky_light: 0.0, tile: Vec4::new(0.0, 0.0, 1.0, 1.0), layer: 0 },
+                            VSInput { position: Vec3::new(0.5, -0.5, -1.0), uv: Vec2::new(1., 1.), ao: 1.0, light: 0.0, sky_light: 0.0, tile: Vec4::new(0.0, 0.0, 1.0, 1.0), layer: 0 },
+                             VSInput { position: Vec3::new(-0.8, 0.5, -1.0), uv: Vec2::new(0., 0.,), ao: 1.0, light: 0.0, sky_light: 0.0, tile: Vec4::new(0.0, 0.0, 1.0, 1.0), layer: 0 },
+                             VSInput { position: Vec3::new(0.5, 0.5, -1.0), uv: Vec2::new(1., 0.), ao: 1.0, light: 0.0, sky_light: 0.0, tile: Vec4::new(0.0, 0.0, 1.0, 1.0), layer: 0 }];
 
     device.create_buffer_init(&BufferInitDescriptor {
         label: None,
@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use crate::atlas::BlockUvs;
+use crate::block::BlockRegistry;
+use crate::region::RegionStore;
+use crate::snapshot::ChunkSnapshot;
+use crate::world::{Chunk, ChunkBuilder, ChunkMeshes, MeshingMode, Position};
+use crate::worldgen::WorldGenerator;
+
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+enum JobKind {
+    Load,
+    Mesh
+}
+
+enum Task {
+    Load,
+    Mesh(Box<ChunkSnapshot>)
+}
+
+struct Job {
+    kind: JobKind,
+    position: Position,
+    id: u64,
+    cancelled: Arc<AtomicBool>,
+    task: Task
+}
+
+enum Output {
+    Loaded(io::Result<Chunk>),
+    Meshed(ChunkMeshes)
+}
+
+struct Finished {
+    kind: JobKind,
+    position: Position,
+    id: u64,
+    output: Output
+}
+
+struct Ticket {
+    id: u64,
+    cancelled: Arc<AtomicBool>
+}
+
+pub enum ChunkResult {
+    Loaded(Position, io::Result<Chunk>),
+    Meshed(Position, ChunkMeshes)
+}
+
+// Loads, generates and meshes chunks on background threads. Workers only ever see their own copies of the
+// data and hand back CPU side results, uploading to the GPU stays on the main thread. Each chunk has at most
+// one job of every kind in flight, submitting a new one or cancelling the chunk makes the older results stale
+// and they are dropped in poll.
+pub struct ChunkWorkers {
+    sender: Option<Sender<Job>>,
+    results: Receiver<Finished>,
+    threads: Vec<JoinHandle<()>>,
+    tickets: HashMap<(JobKind, Position), Ticket>,
+    next_id: u64
+}
+
+impl ChunkWorkers {
+    pub fn new(thread_count: usize, registry: Arc<BlockRegistry>, uvs: Arc<BlockUvs>, generator: Arc<dyn WorldGenerator>, store: RegionStore, meshing: MeshingMode) -> Self {
+        let (sender, jobs) = mpsc::channel::<Job>();
+        let (result_sender, results) = mpsc::channel();
+        let jobs = Arc::new(Mutex::new(jobs));
+
+        let threads = (0..thread_count.max(1))
+            .map(|index| {
+                let jobs = jobs.clone();
+                let results = result_sender.clone();
+                let registry = registry.clone();
+                let uvs = uvs.clone();
+                let generator = generator.clone();
+                let store = store.clone();
+
+                thread::Builder::new()
+                    .name(format!("chunk-worker-{}", index))
+                    .spawn(move || loop {
+                        let job = match jobs.lock().unwrap().recv() {
+                            Ok(job) => job,
+                            Err(_) => break
+                        };
+
+                        if job.cancelled.load(Ordering::Relaxed) {
+                            continue;
+                        }
+
+                        let output = match job.task {
+                            Task::Load => Output::Loaded(load_or_generate(&store, generator.as_ref(), &job.position)),
+                            Task::Mesh(snapshot) => Output::Meshed(ChunkBuilder::new(&snapshot, &registry).with_uvs(&uvs).with_mode(meshing).build())
+                        };
+
+                        let finished = Finished {
+                            kind: job.kind,
+                            position: job.position,
+                            id: job.id,
+                            output
+                        };
+
+                        if results.send(finished).is_err() {
+                            break;
+                        }
+                    })
+                    .expect("Failed to spawn chunk worker")
+            })
+            .collect();
+
+        Self {
+            sender: Some(sender),
+            results,
+            threads,
+            tickets: HashMap::new(),
+            next_id: 0
+        }
+    }
+
+    pub fn default_thread_count() -> usize {
+        // Leave a core for the main thread
+        thread::available_parallelism().map_or(1, |count| count.get().saturating_sub(1).max(1))
+    }
+
+    pub fn load(&mut self, position: &Position) {
+        self.submit(JobKind::Load, *position, Task::Load);
+    }
+
+    pub fn mesh(&mut self, snapshot: ChunkSnapshot) {
+        let position = *snapshot.position();
+        self.submit(JobKind::Mesh, position, Task::Mesh(Box::new(snapshot)));
+    }
+
+    pub fn cancel(&mut self, position: &Position) {
+        for kind in [JobKind::Load, JobKind::Mesh] {
+            if let Some(ticket) = self.tickets.remove(&(kind, *position)) {
+                ticket.cancelled.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.tickets.len()
+    }
+
+    pub fn poll(&mut self) -> Vec<ChunkResult> {
+        let mut results = Vec::new();
+
+        while let Ok(finished) = self.results.try_recv() {
+            let key = (finished.kind, finished.position);
+            match self.tickets.get(&key) {
+                Some(ticket) if ticket.id == finished.id => {
+                    self.tickets.remove(&key);
+                }
+                _ => continue
+            }
+
+            results.push(match finished.output {
+                Output::Loaded(chunk) => ChunkResult::Loaded(finished.position, chunk),
+                Output::Meshed(mesh) => ChunkResult::Meshed(finished.position, mesh)
+            });
+        }
+
+        results
+    }
+
+    fn submit(&mut self, kind: JobKind, position: Position, task: Task) {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let ticket = Ticket {
+            id,
+            cancelled: cancelled.clone()
+        };
+        if let Some(previous) = self.tickets.insert((kind, position), ticket) {
+            previous.cancelled.store(true, Ordering::Relaxed);
+        }
+
+        let job = Job {
+            kind,
+            position,
+            id,
+            cancelled,
+            task
+        };
+        self.sender.as_ref().unwrap().send(job).expect("Chunk workers stopped");
+    }
+}
+
+impl Drop for ChunkWorkers {
+    fn drop(&mut self) {
+        for ticket in self.tickets.values() {
+            ticket.cancelled.store(true, Ordering::Relaxed);
+        }
+
+        // Closing the channel lets the workers run out of jobs and exit
+        self.sender = None;
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn load_or_generate(store: &RegionStore, generator: &dyn WorldGenerator, position: &Position) -> io::Result<Chunk> {
+    match store.load_chunk(position)? {
+        Some(chunk) => Ok(chunk),
+        None => Ok(generator.generate_chunk(position))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use super::*;
+    use crate::block::RenderLayer;
+    use crate::world::{World, BLOCK_TYPE_AIR};
+    use crate::worldgen::{HeightmapGenerator, TerrainBlocks, TerrainConfig};
+
+    fn workers(name: &str) -> (ChunkWorkers, Arc<HeightmapGenerator>) {
+        let registry = Arc::new(BlockRegistry::load("blocks.ron"));
+        let generator = Arc::new(HeightmapGenerator::new(5, TerrainConfig::default(), TerrainBlocks::from_registry(&registry)));
+        let directory = std::env::temp_dir().join(format!("test_engine_{}_{}", name, std::process::id()));
+        let workers = ChunkWorkers::new(2, registry, Arc::new(BlockUvs::default()), generator.clone(), RegionStore::new(directory), MeshingMode::PerFace);
+        (workers, generator)
+    }
+
+    fn wait_for(workers: &mut ChunkWorkers) -> Vec<ChunkResult> {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        let mut results = Vec::new();
+        while workers.in_flight() > 0 {
+            assert!(Instant::now() < deadline, "Chunk workers did not finish");
+            results.extend(workers.poll());
+            thread::sleep(Duration::from_millis(1));
+        }
+        results
+    }
+
+    #[test]
+    fn generates_and_meshes_off_thread() {
+        let (mut workers, generator) = workers("workers_generate");
+        let positions = [Position::new(0, 0, 0), Position::new(1, 0, 0), Position::new(0, -1, 0)];
+        for position in positions.iter() {
+            workers.load(position);
+        }
+
+        let mut world = World::new();
+        for result in wait_for(&mut workers) {
+            match result {
+                ChunkResult::Loaded(_, chunk) => world.add_chunk(chunk.unwrap()),
+                ChunkResult::Meshed(..) => panic!("Nothing was meshed yet")
+            }
+        }
+
+        assert_eq!(world.chunks.len(), 3);
+        let expected = generator.generate_chunk(&Position::new(1, 0, 0));
+        let loaded = world.chunks[&Position::new(1, 0, 0)].storage();
+        assert!((0..loaded.len()).all(|index| loaded.get(index) == expected.storage().get(index)));
+
+        workers.mesh(ChunkSnapshot::capture(&world, &Position::new(0, 0, 0)).unwrap());
+        let results = wait_for(&mut workers);
+        assert!(matches!(&results[..], [ChunkResult::Meshed(position, mesh)] if *position == Position::new(0, 0, 0) && !mesh.is_empty()));
+    }
+
+    #[test]
+    fn drops_results_of_replaced_and_cancelled_jobs() {
+        let (mut workers, _) = workers("workers_cancel");
+
+        let mut world = World::new();
+        world.set_block(&Position::new(0, 0, 0), 1);
+        let first = ChunkSnapshot::capture(&world, &Position::new(0, 0, 0)).unwrap();
+        world.set_block(&Position::new(0, 0, 0), BLOCK_TYPE_AIR);
+        world.set_block(&Position::new(3, 0, 0), 1);
+        world.set_block(&Position::new(5, 0, 0), 1);
+        let second = ChunkSnapshot::capture(&world, &Position::new(0, 0, 0)).unwrap();
+
+        // The chunk was edited while the first job was queued, only the newest mesh may come back
+        workers.mesh(first);
+        workers.mesh(second);
+        workers.load(&Position::new(4, 4, 4));
+        workers.cancel(&Position::new(4, 4, 4));
+
+        let results = wait_for(&mut workers);
+        assert_eq!(results.len(), 1);
+        match &results[0] {
+            ChunkResult::Meshed(_, mesh) => assert_eq!(mesh.layer(RenderLayer::Opaque).indices().len(), 2 * 6 * 6),
+            ChunkResult::Loaded(..) => panic!("Cancelled load was delivered")
+        }
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(workers.poll().is_empty());
+    }
+}
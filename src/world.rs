@@ -1,11 +1,16 @@
-use std::collections::HashMap;
-use std::slice;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io;
+use std::path::Path;
 use glam::{Vec2, Vec3};
-use wgpu::{Buffer, Device};
-use wgpu::util::{BufferInitDescriptor, DeviceExt};
+use crate::atlas::{BlockUvs, FaceUv};
+use crate::block::{BlockRegistry, Face, RenderLayer};
 use crate::buffer_builder::BufferBuilder;
+use crate::coords;
+use crate::light::MAX_LIGHT;
+use crate::palette::PalettedStorage;
+use crate::region::RegionStore;
+use crate::snapshot::ChunkSnapshot;
 use crate::util::VSInput;
-use crate::world;
 
 pub const CHUNK_SIZE_X: i64 = 16;
 pub const CHUNK_SIZE_Y: i64 = 16;
@@ -13,163 +18,580 @@ pub const CHUNK_SIZE_Z: i64 = 16;
 pub const CHUNK_SIZE_XYZ: i64 = CHUNK_SIZE_X * CHUNK_SIZE_Y * CHUNK_SIZE_Z;
 
 pub const BLOCK_TYPE_AIR: u16 = 0;
-pub const BLOCK_TYPE_STONE: u16 = 1;
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone)]
 pub struct Chunk {
-    data: Vec<u16>,
+    data: PalettedStorage,
+    // Block light in the low and skylight in the high four bits, left empty while the whole chunk is dark
+    light: Vec<u8>,
+    // Local height of the highest opaque block in every column, -1 when the column has none
+    heightmap: Vec<i8>,
     position: Position
 }
 
 impl Chunk {
     pub fn new(position: Position, filled_with: u16) -> Self {
-        let mut data = (0..CHUNK_SIZE_XYZ).map(|_| filled_with).collect::<Vec<_>>();
+        Self::from_storage(position, PalettedStorage::new(CHUNK_SIZE_XYZ as usize, filled_with))
+    }
+
+    pub fn from_storage(position: Position, data: PalettedStorage) -> Self {
+        assert_eq!(data.len(), CHUNK_SIZE_XYZ as usize, "Chunk storage has the wrong size");
         Self {
             position,
-            data
+            data,
+            light: Vec::new(),
+            heightmap: vec![-1; (CHUNK_SIZE_X * CHUNK_SIZE_Z) as usize]
         }
     }
 
+    pub fn storage(&self) -> &PalettedStorage {
+        &self.data
+    }
+
+    pub fn position(&self) -> &Position {
+        &self.position
+    }
+
+    pub fn is_uniform(&self) -> bool {
+        self.data.is_uniform()
+    }
+
     pub fn set_block(&mut self, block_type: u16, block_position: &Position) {
-        self.data[(block_position.z * CHUNK_SIZE_X * CHUNK_SIZE_Y + block_position.y * CHUNK_SIZE_X + block_position.x) as usize] = block_type;
+        self.data.set(Self::index(block_position), block_type);
     }
 
     pub fn get_block(&self, block_position: &Position) -> u16 {
-        self.data[(block_position.z * CHUNK_SIZE_X * CHUNK_SIZE_Y + block_position.y * CHUNK_SIZE_X + block_position.x) as usize]
+        self.data.get(Self::index(block_position))
+    }
+
+    pub fn get_block_light(&self, block_position: &Position) -> u8 {
+        self.packed_light(block_position) & 0x0f
+    }
+
+    pub fn set_block_light(&mut self, block_position: &Position, level: u8) {
+        let packed = self.packed_light(block_position) & 0xf0 | level;
+        self.set_packed_light(block_position, packed);
+    }
+
+    pub fn get_sky_light(&self, block_position: &Position) -> u8 {
+        self.packed_light(block_position) >> 4
+    }
+
+    pub fn set_sky_light(&mut self, block_position: &Position, level: u8) {
+        let packed = self.packed_light(block_position) & 0x0f | level << 4;
+        self.set_packed_light(block_position, packed);
+    }
+
+    pub fn packed_light(&self, block_position: &Position) -> u8 {
+        self.light.get(Self::index(block_position)).copied().unwrap_or(0)
+    }
+
+    fn set_packed_light(&mut self, block_position: &Position, packed: u8) {
+        if self.light.is_empty() {
+            if packed == 0 {
+                return;
+            }
+            self.light = vec![0; CHUNK_SIZE_XYZ as usize];
+        }
+        self.light[Self::index(block_position)] = packed;
+    }
+
+    // Local y of the highest opaque block in the column, if there is one
+    pub fn height(&self, x: i64, z: i64) -> Option<i64> {
+        let height = self.heightmap[(z * CHUNK_SIZE_X + x) as usize];
+        if height < 0 { None } else { Some(height as i64) }
+    }
+
+    // Keeps the heightmap in sync with a block that was just set
+    pub fn update_height(&mut self, block_position: &Position, opaque: impl Fn(u16) -> bool) {
+        let column = (block_position.z * CHUNK_SIZE_X + block_position.x) as usize;
+        let height = self.heightmap[column] as i64;
+
+        if opaque(self.get_block(block_position)) {
+            self.heightmap[column] = height.max(block_position.y) as i8;
+        } else if block_position.y == height {
+            self.heightmap[column] = (0..height).rev()
+                .find(|y| opaque(self.get_block(&Position::new(block_position.x, *y, block_position.z))))
+                .map_or(-1, |y| y as i8);
+        }
+    }
+
+    pub fn rebuild_heightmap(&mut self, opaque: impl Fn(u16) -> bool) {
+        // Uniform chunks are either solid or empty in every column
+        if self.is_uniform() {
+            let height = if opaque(self.data.get(0)) { CHUNK_SIZE_Y - 1 } else { -1 };
+            self.heightmap.fill(height as i8);
+            return;
+        }
+
+        for x in 0..CHUNK_SIZE_X {
+            for z in 0..CHUNK_SIZE_Z {
+                self.heightmap[(z * CHUNK_SIZE_X + x) as usize] = (0..CHUNK_SIZE_Y).rev()
+                    .find(|y| opaque(self.get_block(&Position::new(x, *y, z))))
+                    .map_or(-1, |y| y as i8);
+            }
+        }
+    }
+
+    fn index(block_position: &Position) -> usize {
+        (block_position.z * CHUNK_SIZE_X * CHUNK_SIZE_Y + block_position.y * CHUNK_SIZE_X + block_position.x) as usize
     }
 }
 
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new(Position::default(), BLOCK_TYPE_AIR)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum MeshingMode {
+    // One quad for every visible block face
+    PerFace,
+    // Coplanar neighbouring faces of the same block type are merged into larger rectangles
+    #[default]
+    Greedy
+}
+
+// Vertex and index data of a chunk, one mesh for each render layer
+#[derive(Default)]
+pub struct ChunkMeshes {
+    layers: [BufferBuilder; 3]
+}
+
+impl ChunkMeshes {
+    pub fn layer(&self, layer: RenderLayer) -> &BufferBuilder {
+        &self.layers[layer as usize]
+    }
+
+    pub fn layer_mut(&mut self, layer: RenderLayer) -> &mut BufferBuilder {
+        &mut self.layers[layer as usize]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.iter().all(|layer| layer.is_empty())
+    }
+}
+
+// Builds the vertex and index data of a chunk on the CPU, so it can run on a worker thread
 pub struct ChunkBuilder<'a> {
-    chunk: &'a Chunk,
-    buffer_builder: BufferBuilder
+    snapshot: &'a ChunkSnapshot,
+    registry: &'a BlockRegistry,
+    uvs: Option<&'a BlockUvs>,
+    mode: MeshingMode,
+    meshes: ChunkMeshes
 }
 
 impl<'a> ChunkBuilder<'a> {
-    pub fn new(chunk: &'a Chunk) -> Self {
+    pub fn new(snapshot: &'a ChunkSnapshot, registry: &'a BlockRegistry) -> Self {
         Self {
-            chunk,
-            buffer_builder: BufferBuilder::new()
+            snapshot,
+            registry,
+            uvs: None,
+            mode: MeshingMode::default(),
+            meshes: ChunkMeshes::default()
         }
     }
 
-    fn add_bottom_face(&mut self, block_position: &Position) {
+    pub fn with_mode(mut self, mode: MeshingMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    // Without atlas rectangles every face covers the whole texture
+    pub fn with_uvs(mut self, uvs: &'a BlockUvs) -> Self {
+        self.uvs = Some(uvs);
+        self
+    }
+
+    // Size is the extent of the face in blocks, its component along the face normal is ignored. Texture
+    // coordinates grow with the size, the shader wraps them into the tile so it repeats once per block.
+    fn add_face(&mut self, face: Face, block_position: &Position, size: &Position, cell: &FaceCell) {
+        let vertices = match face {
+            Face::Bottom => Self::bottom_face(block_position, size),
+            Face::Top => Self::top_face(block_position, size),
+            Face::West => Self::west_face(block_position, size),
+            Face::East => Self::east_face(block_position, size),
+            Face::North => Self::north_face(block_position, size),
+            Face::South => Self::south_face(block_position, size)
+        };
+
+        let uv = self.uvs.map_or(FaceUv::FULL, |uvs| uvs.get(cell.block, face));
+        let axes = FaceAxes::of(face);
+        let [a, b, c, d] = vertices.map(|vertex| {
+            let u_high = vertex.position()[axes.u] > FaceAxes::component(block_position, axes.u) as f32;
+            let v_high = vertex.position()[axes.v] > FaceAxes::component(block_position, axes.v) as f32;
+            let corner = u_high as usize | (v_high as usize) << 1;
+            let vertex = if cell.is_full() { vertex } else { cell.clip(face, vertex, block_position.y) };
+            vertex.with_ao(cell.ao[corner] as f32 / 3.0)
+                .with_light(cell.light[corner] as f32 / (4 * MAX_LIGHT) as f32)
+                .with_sky_light(cell.sky_light[corner] as f32 / (4 * MAX_LIGHT) as f32)
+                .with_tile(uv.rect.to_vec4())
+                .with_layer(uv.layer)
+        });
+
+        // Split the quad along its darker diagonal, otherwise the interpolation of anisotropic occlusion
+        // shows a visible crease
+        let buffer_builder = self.meshes.layer_mut(self.registry.render_layer(cell.block));
+        if a.ao() + c.ao() > b.ao() + d.ao() {
+            buffer_builder.add_quad(b, c, d, a);
+        } else {
+            buffer_builder.add_quad(a, b, c, d);
+        }
+    }
+
+    fn bottom_face(block_position: &Position, size: &Position) -> [VSInput; 4] {
         let x = block_position.x as f32;
         let y = block_position.y as f32;
         let z = block_position.z as f32;
+        let w = size.x as f32;
+        let d = size.z as f32;
 
-        self.buffer_builder.add_quad(
-            VSInput::new(Vec3::new(x + 1f32, y, z), Vec2::new(1.0f32, 0.0f32)),
+        [
+            VSInput::new(Vec3::new(x + w, y, z), Vec2::new(w, 0.0f32)),
             VSInput::new(Vec3::new(x, y, z), Vec2::new(0.0f32, 0.0f32)),
-            VSInput::new(Vec3::new(x, y, z + 1f32), Vec2::new(0f32, 1f32)),
-            VSInput::new(Vec3::new(x + 1f32, y, z + 1f32), Vec2::new(1.0f32, 1.0f32)),
-        );
+            VSInput::new(Vec3::new(x, y, z + d), Vec2::new(0f32, d)),
+            VSInput::new(Vec3::new(x + w, y, z + d), Vec2::new(w, d)),
+        ]
     }
 
-    fn add_top_face(&mut self, block_position: &Position) {
+    fn top_face(block_position: &Position, size: &Position) -> [VSInput; 4] {
         let x = block_position.x as f32;
         let y = block_position.y as f32;
         let z = block_position.z as f32;
+        let w = size.x as f32;
+        let d = size.z as f32;
 
-        self.buffer_builder.add_quad(
-            VSInput::new(Vec3::new(x, y + 1f32, z), Vec2::new(0.0f32, 1.0f32)),
-            VSInput::new(Vec3::new(x + 1f32, y + 1f32, z), Vec2::new(1.0f32, 1.0f32)),
-            VSInput::new(Vec3::new(x + 1f32, y + 1f32, z + 1f32), Vec2::new(1.0f32, 0.0f32)),
-            VSInput::new(Vec3::new(x, y + 1f32, z + 1f32), Vec2::new(0f32, 0f32))
-        );
+        [
+            VSInput::new(Vec3::new(x, y + 1f32, z), Vec2::new(0.0f32, d)),
+            VSInput::new(Vec3::new(x + w, y + 1f32, z), Vec2::new(w, d)),
+            VSInput::new(Vec3::new(x + w, y + 1f32, z + d), Vec2::new(w, 0.0f32)),
+            VSInput::new(Vec3::new(x, y + 1f32, z + d), Vec2::new(0f32, 0f32))
+        ]
     }
 
-    fn add_west_face(&mut self, block_position: &Position) {
+    fn west_face(block_position: &Position, size: &Position) -> [VSInput; 4] {
         let x = block_position.x as f32;
         let y = block_position.y as f32;
         let z = block_position.z as f32;
+        let h = size.y as f32;
+        let d = size.z as f32;
 
-        self.buffer_builder.add_quad(
-            VSInput::new(Vec3::new(x, y + 1f32, z), Vec2::new(1.0f32, 0.0f32)),
-            VSInput::new(Vec3::new(x, y + 1f32, z + 1f32), Vec2::new(0.0f32, 0.0f32)),
-            VSInput::new(Vec3::new(x, y, z + 1f32), Vec2::new(0.0f32, 1.0f32)),
-            VSInput::new(Vec3::new(x, y, z), Vec2::new(1.0f32, 1.0f32))
-        );
+        [
+            VSInput::new(Vec3::new(x, y + h, z), Vec2::new(d, 0.0f32)),
+            VSInput::new(Vec3::new(x, y + h, z + d), Vec2::new(0.0f32, 0.0f32)),
+            VSInput::new(Vec3::new(x, y, z + d), Vec2::new(0.0f32, h)),
+            VSInput::new(Vec3::new(x, y, z), Vec2::new(d, h))
+        ]
     }
 
-    fn add_east_face(&mut self, block_position: &Position) {
+    fn east_face(block_position: &Position, size: &Position) -> [VSInput; 4] {
         let x = block_position.x as f32;
         let y = block_position.y as f32;
         let z = block_position.z as f32;
+        let h = size.y as f32;
+        let d = size.z as f32;
 
-        self.buffer_builder.add_quad(
-            VSInput::new(Vec3::new(x + 1f32, y + 1f32, z + 1f32), Vec2::new(1.0f32, 0.0f32)),
-            VSInput::new(Vec3::new(x + 1f32, y + 1f32, z), Vec2::new(0.0f32, 0.0f32)),
-            VSInput::new(Vec3::new(x + 1f32, y, z), Vec2::new(0.0f32, 1.0f32)),
-            VSInput::new(Vec3::new(x + 1f32, y, z + 1f32), Vec2::new(1.0f32, 1.0f32)),
-        );
+        [
+            VSInput::new(Vec3::new(x + 1f32, y + h, z + d), Vec2::new(d, 0.0f32)),
+            VSInput::new(Vec3::new(x + 1f32, y + h, z), Vec2::new(0.0f32, 0.0f32)),
+            VSInput::new(Vec3::new(x + 1f32, y, z), Vec2::new(0.0f32, h)),
+            VSInput::new(Vec3::new(x + 1f32, y, z + d), Vec2::new(d, h)),
+        ]
     }
 
-    fn add_north_face(&mut self, block_position: &Position) {
+    fn north_face(block_position: &Position, size: &Position) -> [VSInput; 4] {
         let x = block_position.x as f32;
         let y = block_position.y as f32;
         let z = block_position.z as f32;
+        let w = size.x as f32;
+        let h = size.y as f32;
 
-        self.buffer_builder.add_quad(
-            VSInput::new(Vec3::new(x, y + 1f32, z + 1f32), Vec2::new(1.0f32, 0.0f32)),
-            VSInput::new(Vec3::new(x + 1f32, y + 1f32, z + 1f32), Vec2::new(0.0f32, 0.0f32)),
-            VSInput::new(Vec3::new(x + 1f32, y, z + 1f32), Vec2::new(0.0f32, 1.0f32)),
-            VSInput::new(Vec3::new(x, y, z + 1f32), Vec2::new(1.0f32, 1.0f32))
-        );
+        [
+            VSInput::new(Vec3::new(x, y + h, z + 1f32), Vec2::new(w, 0.0f32)),
+            VSInput::new(Vec3::new(x + w, y + h, z + 1f32), Vec2::new(0.0f32, 0.0f32)),
+            VSInput::new(Vec3::new(x + w, y, z + 1f32), Vec2::new(0.0f32, h)),
+            VSInput::new(Vec3::new(x, y, z + 1f32), Vec2::new(w, h))
+        ]
     }
 
-    fn add_south_face(&mut self, block_position: &Position) {
+    fn south_face(block_position: &Position, size: &Position) -> [VSInput; 4] {
         let x = block_position.x as f32;
         let y = block_position.y as f32;
         let z = block_position.z as f32;
+        let w = size.x as f32;
+        let h = size.y as f32;
 
-        self.buffer_builder.add_quad(
-            VSInput::new(Vec3::new(x, y, z), Vec2::new(0.0f32, 1.0f32)),
-            VSInput::new(Vec3::new(x + 1f32, y, z), Vec2::new(1.0f32, 1.0f32)),
-            VSInput::new(Vec3::new(x + 1f32, y + 1f32, z), Vec2::new(1.0f32, 0.0f32)),
-            VSInput::new(Vec3::new(x, y + 1f32, z), Vec2::new(0.0f32, 0.0f32)),
-        );
+        [
+            VSInput::new(Vec3::new(x, y, z), Vec2::new(0.0f32, h)),
+            VSInput::new(Vec3::new(x + w, y, z), Vec2::new(w, h)),
+            VSInput::new(Vec3::new(x + w, y + h, z), Vec2::new(w, 0.0f32)),
+            VSInput::new(Vec3::new(x, y + h, z), Vec2::new(0.0f32, 0.0f32)),
+        ]
     }
 
-    pub fn build_mesh(&mut self, device: &Device, world: &World) -> (Buffer, Buffer, u32) {
+    // Block type and corner occlusion of the face at the given local position, if it is visible
+    fn visible_face(&self, face: Face, local_position: &Position) -> Option<FaceCell> {
+        let block = self.snapshot.get_block(local_position);
+        if block == BLOCK_TYPE_AIR {
+            return None;
+        }
+
+        let normal = face.normal();
+        let neighbour_position = local_position.offset(normal.x, normal.y, normal.z);
+        let neighbour = self.snapshot.get_block(&neighbour_position);
+        let bounds = match self.fluid_height(local_position) {
+            Some(height) => self.fluid_face_bounds(face, block, neighbour, height, &neighbour_position)?,
+            None if self.registry.is_face_visible(block, neighbour) => FaceCell::FULL_BOUNDS,
+            None => return None
+        };
+
+        let (ao, light, sky_light) = self.face_corners(face, local_position);
+        Some(FaceCell {
+            block,
+            bounds,
+            ao,
+            light,
+            sky_light
+        })
+    }
+
+    // Height of the fluid surface in sixteenths of a block, sources fill most of it and every level a little
+    // less. Fluids below more of the same fluid fill their whole block so falling columns have no gaps.
+    fn fluid_height(&self, local_position: &Position) -> Option<u8> {
+        let block = self.snapshot.get_block(local_position);
+        let fluid = self.registry.fluid(block)?;
+        if self.registry.is_same_fluid(block, self.snapshot.get_block(&local_position.offset(0, 1, 0))) {
+            return Some(16);
+        }
+
+        let levels = fluid.levels as u32 + 1;
+        Some((14 * (levels - fluid.level as u32) / levels).max(1) as u8)
+    }
+
+    // Vertical extent of a fluid face. Surfaces below the top of their block always show, and the sides between
+    // two levels of the same fluid show the step from the lower surface up to the higher one.
+    fn fluid_face_bounds(&self, face: Face, block: u16, neighbour: u16, height: u8, neighbour_position: &Position) -> Option<[u8; 2]> {
+        match face {
+            Face::Top if height < 16 => Some([0, height]),
+            Face::Top | Face::Bottom => self.registry.is_face_visible(block, neighbour).then_some([0, height]),
+            _ if self.registry.is_same_fluid(block, neighbour) => {
+                let below = self.fluid_height(neighbour_position).unwrap();
+                (below < height).then_some([below, height])
+            }
+            _ => self.registry.is_face_visible(block, neighbour).then_some([0, height])
+        }
+    }
+
+    // Each corner is shaded by the blocks next to it in the layer in front of the face, indexed by whether the
+    // corner sits on the high side of the u and v axes. Occlusion counts the opaque blocks, light is averaged
+    // over the transparent ones and kept at four times the resolution of a light level.
+    fn face_corners(&self, face: Face, local_position: &Position) -> ([u8; 4], [u8; 4], [u8; 4]) {
+        let axes = FaceAxes::of(face);
+        let normal = face.normal();
+        let front = local_position.offset(normal.x, normal.y, normal.z);
+        let block_at = |offset: &Position| front.offset(offset.x, offset.y, offset.z);
+
+        let mut ao = [0; 4];
+        let mut light = [0; 4];
+        let mut sky_light = [0; 4];
+        for corner in 0..4 {
+            let u = if corner & 1 == 0 { -1 } else { 1 };
+            let v = if corner & 2 == 0 { -1 } else { 1 };
+
+            let side_u = block_at(&axes.position(0, u, 0));
+            let side_v = block_at(&axes.position(0, 0, v));
+            let diagonal = block_at(&axes.position(0, u, v));
+            let opaque = [side_u, side_v, diagonal].map(|position| self.registry.is_opaque(self.snapshot.get_block(&position)));
+            ao[corner] = vertex_ao(opaque[0], opaque[1], opaque[2]);
+
+            // Light does not leak through the diagonal when both sides are closed
+            let open = [!opaque[0], !opaque[1], !(opaque[2] || opaque[0] && opaque[1])];
+            let average = |level: &dyn Fn(&Position) -> u8| {
+                let mut sum = level(&front) as u32;
+                let mut count = 1;
+                for (index, position) in [side_u, side_v, diagonal].iter().enumerate() {
+                    if open[index] {
+                        sum += level(position) as u32;
+                        count += 1;
+                    }
+                }
+                ((sum * 4 + count / 2) / count) as u8
+            };
+
+            light[corner] = average(&|position| self.snapshot.get_block_light(position));
+            sky_light[corner] = average(&|position| self.snapshot.get_sky_light(position));
+        }
+
+        (ao, light, sky_light)
+    }
+
+    pub fn build(mut self) -> ChunkMeshes {
+        match self.mode {
+            MeshingMode::PerFace => self.build_per_face(),
+            MeshingMode::Greedy => self.build_greedy()
+        }
+
+        self.meshes
+    }
+
+    fn build_per_face(&mut self) {
+        let chunk_position = *self.snapshot.position();
         for x in 0..CHUNK_SIZE_X {
             for y in 0..CHUNK_SIZE_Y {
                 for z in 0..CHUNK_SIZE_Z {
-                    let chunk_position = Position::new(x, y, z);
-                    let position = Position::new(self.chunk.position.x * CHUNK_SIZE_X + x,
-                                                     self.chunk.position.y * CHUNK_SIZE_Y + y,
-                                                 self.chunk.position.z * CHUNK_SIZE_Z + z);
-                    if self.chunk.get_block(&chunk_position) == BLOCK_TYPE_AIR {
-                        continue;
-                    }
+                    let local_position = Position::new(x, y, z);
+                    let position = coords::local_to_world(&chunk_position, &local_position);
 
-                    if world.get_block(&Position::new(position.x, position.y - 1, position.z)) == BLOCK_TYPE_AIR {
-                        self.add_bottom_face(&chunk_position);
+                    for face in Face::ALL {
+                        if let Some(cell) = self.visible_face(face, &local_position) {
+                            self.add_face(face, &position, &Position::new(1, 1, 1), &cell);
+                        }
                     }
+                }
+            }
+        }
+    }
 
-                    if world.get_block(&Position::new(position.x, position.y + 1, position.z)) == BLOCK_TYPE_AIR {
-                        self.add_top_face(&chunk_position);
-                    }
+    fn build_greedy(&mut self) {
+        let chunk_position = *self.snapshot.position();
 
-                    if world.get_block(&Position::new(position.x - 1, position.y, position.z)) == BLOCK_TYPE_AIR {
-                        self.add_west_face(&chunk_position);
-                    }
+        for face in Face::ALL {
+            let axes = FaceAxes::of(face);
+            let (width, height) = (axes.u_size(), axes.v_size());
+            let mut mask = vec![None; (width * height) as usize];
 
-                    if world.get_block(&Position::new(position.x + 1, position.y, position.z)) == BLOCK_TYPE_AIR {
-                        self.add_east_face(&chunk_position);
+            for depth in 0..axes.depth_size() {
+                for v in 0..height {
+                    for u in 0..width {
+                        mask[(v * width + u) as usize] = self.visible_face(face, &axes.position(depth, u, v));
                     }
+                }
 
-                    if world.get_block(&Position::new(position.x, position.y, position.z + 1)) == BLOCK_TYPE_AIR {
-                        self.add_north_face(&chunk_position);
-                    }
+                for v in 0..height {
+                    let mut u = 0;
+                    while u < width {
+                        let cell = match mask[(v * width + u) as usize] {
+                            Some(cell) => cell,
+                            None => {
+                                u += 1;
+                                continue;
+                            }
+                        };
+
+                        let mut quad_width = 1;
+                        while u + quad_width < width && mask[(v * width + u + quad_width) as usize] == Some(cell) {
+                            quad_width += 1;
+                        }
 
-                    if world.get_block(&Position::new(position.x, position.y, position.z - 1)) == BLOCK_TYPE_AIR {
-                        self.add_south_face(&chunk_position);
+                        // Partial faces are clipped within their own block, so they never stack along y
+                        let mut quad_height = 1;
+                        while v + quad_height < height
+                            && (cell.is_full() || axes.v != 1)
+                            && (u..u + quad_width).all(|x| mask[((v + quad_height) * width + x) as usize] == Some(cell)) {
+                            quad_height += 1;
+                        }
+
+                        for row in v..v + quad_height {
+                            for x in u..u + quad_width {
+                                mask[(row * width + x) as usize] = None;
+                            }
+                        }
+
+                        let position = coords::local_to_world(&chunk_position, &axes.position(depth, u, v));
+                        self.add_face(face, &position, &axes.size(quad_width, quad_height), &cell);
+                        u += quad_width;
                     }
                 }
             }
         }
+    }
+}
+
+// Faces only merge when the block, its bounds and the shading of every corner match
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct FaceCell {
+    block: u16,
+    // Bottom and top of the face in sixteenths of a block, only fluids are not full
+    bounds: [u8; 2],
+    ao: [u8; 4],
+    light: [u8; 4],
+    sky_light: [u8; 4]
+}
+
+impl FaceCell {
+    const FULL_BOUNDS: [u8; 2] = [0, 16];
+
+    fn is_full(&self) -> bool {
+        self.bounds == Self::FULL_BOUNDS
+    }
+
+    // Moves a vertex of a one block high face onto the bounds, side textures are cut off instead of squashed
+    fn clip(&self, face: Face, vertex: VSInput, y: i64) -> VSInput {
+        let mut position = vertex.position();
+        let mut uv = vertex.uv();
+        let bound = self.bounds[(position.y > y as f32) as usize] as f32 / 16.0;
+        position.y = y as f32 + bound;
+        if !matches!(face, Face::Top | Face::Bottom) {
+            uv.y = 1.0 - bound;
+        }
+        VSInput::new(position, uv)
+    }
+}
+
+// 3 is fully lit, a corner between two occluding sides gets no light no matter what the diagonal holds
+fn vertex_ao(side_u: bool, side_v: bool, diagonal: bool) -> u8 {
+    if side_u && side_v {
+        0
+    } else {
+        3 - side_u as u8 - side_v as u8 - diagonal as u8
+    }
+}
+
+// Maps the slice depth along a face normal and the two in-plane coordinates back to chunk coordinates
+struct FaceAxes {
+    normal: usize,
+    u: usize,
+    v: usize
+}
+
+impl FaceAxes {
+    const SIZES: [i64; 3] = [CHUNK_SIZE_X, CHUNK_SIZE_Y, CHUNK_SIZE_Z];
+
+    fn of(face: Face) -> Self {
+        match face {
+            Face::Bottom | Face::Top => Self { normal: 1, u: 0, v: 2 },
+            Face::West | Face::East => Self { normal: 0, u: 2, v: 1 },
+            Face::North | Face::South => Self { normal: 2, u: 0, v: 1 }
+        }
+    }
+
+    fn depth_size(&self) -> i64 {
+        Self::SIZES[self.normal]
+    }
+
+    fn u_size(&self) -> i64 {
+        Self::SIZES[self.u]
+    }
+
+    fn v_size(&self) -> i64 {
+        Self::SIZES[self.v]
+    }
 
-        self.buffer_builder.build(device)
+    fn position(&self, depth: i64, u: i64, v: i64) -> Position {
+        let mut axes = [0; 3];
+        axes[self.normal] = depth;
+        axes[self.u] = u;
+        axes[self.v] = v;
+        Position::new(axes[0], axes[1], axes[2])
+    }
+
+    fn component(position: &Position, axis: usize) -> i64 {
+        [position.x, position.y, position.z][axis]
+    }
+
+    fn size(&self, width: i64, height: i64) -> Position {
+        self.position(1, width, height)
     }
 }
 
@@ -184,31 +606,720 @@ impl Position {
     pub fn new(x: i64, y: i64, z: i64) -> Self {
         Self { x, y, z }
     }
+
+    pub fn offset(&self, x: i64, y: i64, z: i64) -> Self {
+        Self::new(self.x + x, self.y + y, self.z + z)
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct World {
-    pub chunks: HashMap<Position, Chunk>
+    pub chunks: HashMap<Position, Chunk>,
+    dirty_chunks: HashSet<Position>,
+    // Chunks changed since they were loaded or generated, only these need to be written back when evicted
+    modified_chunks: HashSet<Position>,
+    // Loaded chunk heights of every chunk column, used to walk columns from the top
+    columns: HashMap<(i64, i64), BTreeSet<i64>>,
+    // Opacity by block id for the heightmaps. Without a registry every block but air counts as opaque.
+    opaque: Vec<bool>,
+    // Blocks rays stop at, everything but air and fluids
+    targetable: Vec<bool>
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RaycastHit {
+    pub position: Position,
+    // Points out of the face the ray entered through, zero when the ray starts inside the block
+    pub normal: Position,
+    pub distance: f32
 }
 
 impl World {
     pub fn new() -> Self {
         Self {
-            chunks: HashMap::new()
+            chunks: HashMap::new(),
+            dirty_chunks: HashSet::new(),
+            modified_chunks: HashSet::new(),
+            columns: HashMap::new(),
+            opaque: Vec::new(),
+            targetable: Vec::new()
+        }
+    }
+
+    pub fn with_registry(registry: &BlockRegistry) -> Self {
+        let mut opaque = Vec::new();
+        let mut targetable = Vec::new();
+        for block in registry.iter() {
+            let index = block.id as usize;
+            if opaque.len() <= index {
+                opaque.resize(index + 1, false);
+                targetable.resize(index + 1, false);
+            }
+            opaque[index] = block.opaque;
+            targetable[index] = block.id != BLOCK_TYPE_AIR && registry.fluid(block.id).is_none();
+        }
+
+        Self {
+            opaque,
+            targetable,
+            ..Self::new()
         }
     }
 
-    pub fn add_chunk(&mut self, chunk: Chunk) {
-        self.chunks.insert(chunk.position, chunk);
+    pub fn is_opaque(&self, block: u16) -> bool {
+        block_flag(&self.opaque, block)
+    }
+
+    // First block along the ray that is not air or a fluid, stepping from block to block with the traversal of
+    // Amanatides and Woo. Chunks that are not loaded count as air.
+    pub fn raycast(&self, origin: Vec3, direction: Vec3, max_distance: f32) -> Option<RaycastHit> {
+        // An endless ray through empty space would never stop stepping
+        let direction = direction.normalize_or_zero();
+        if direction == Vec3::ZERO || !max_distance.is_finite() || !origin.is_finite() {
+            return None;
+        }
+
+        let start = origin.floor();
+        let mut block = [start.x as i64, start.y as i64, start.z as i64];
+        let mut step = [0; 3];
+        // Distance along the ray to the next block boundary on every axis, and between two boundaries
+        let mut next = [f32::INFINITY; 3];
+        let mut delta = [f32::INFINITY; 3];
+        for axis in 0..3 {
+            if direction[axis] > 0.0 {
+                step[axis] = 1;
+                next[axis] = (start[axis] + 1.0 - origin[axis]) / direction[axis];
+            } else if direction[axis] < 0.0 {
+                step[axis] = -1;
+                next[axis] = (start[axis] - origin[axis]) / direction[axis];
+            }
+            if step[axis] != 0 {
+                delta[axis] = 1.0 / direction[axis].abs();
+            }
+        }
+
+        let mut normal = [0; 3];
+        let mut distance = 0.0;
+        loop {
+            let position = Position::new(block[0], block[1], block[2]);
+            if block_flag(&self.targetable, self.get_block(&position)) {
+                return Some(RaycastHit {
+                    position,
+                    normal: Position::new(normal[0], normal[1], normal[2]),
+                    distance
+                });
+            }
+
+            let axis = if next[0] < next[1] && next[0] < next[2] { 0 } else if next[1] < next[2] { 1 } else { 2 };
+            distance = next[axis];
+            if distance > max_distance {
+                return None;
+            }
+
+            block[axis] += step[axis];
+            normal = [0; 3];
+            normal[axis] = -step[axis];
+            next[axis] += delta[axis];
+        }
+    }
+
+    // The registry decides which blocks are opaque or targetable, like it does for generated worlds
+    pub fn load(directory: impl AsRef<Path>, registry: &BlockRegistry) -> io::Result<Self> {
+        let store = RegionStore::new(directory);
+        let mut world = Self::with_registry(registry);
+
+        for position in store.stored_chunks()? {
+            if let Some(chunk) = store.load_chunk(&position)? {
+                world.add_chunk(chunk);
+            }
+        }
+
+        Ok(world)
+    }
+
+    pub fn save(&self, directory: impl AsRef<Path>) -> io::Result<()> {
+        RegionStore::new(directory).save_chunks(self.chunks.values())
+    }
+
+    pub fn add_chunk(&mut self, mut chunk: Chunk) {
+        let position = chunk.position;
+        chunk.rebuild_heightmap(|block| block_flag(&self.opaque, block));
+        self.chunks.insert(position, chunk);
+        self.columns.entry((position.x, position.z)).or_default().insert(position.y);
+
+        // Faces along the borders of the neighbours may now be hidden by the new chunk
+        for x in -1..=1 {
+            for y in -1..=1 {
+                for z in -1..=1 {
+                    self.mark_dirty(&position.offset(x, y, z));
+                }
+            }
+        }
+    }
+
+    pub fn remove_chunk(&mut self, position: &Position) -> Option<Chunk> {
+        let chunk = self.chunks.remove(position)?;
+        self.dirty_chunks.remove(position);
+        self.modified_chunks.remove(position);
+        if let Some(column) = self.columns.get_mut(&(position.x, position.z)) {
+            column.remove(&position.y);
+            if column.is_empty() {
+                self.columns.remove(&(position.x, position.z));
+            }
+        }
+
+        // Border faces of the neighbours are exposed again
+        for x in -1..=1 {
+            for y in -1..=1 {
+                for z in -1..=1 {
+                    self.mark_dirty(&position.offset(x, y, z));
+                }
+            }
+        }
+
+        Some(chunk)
+    }
+
+    pub fn is_modified(&self, chunk_position: &Position) -> bool {
+        self.modified_chunks.contains(chunk_position)
     }
 
     pub fn get_block(&self, position: &Position) -> u16 {
-        let chunk_position = Position::new(position.x >> 4, position.y >> 4, position.z >> 4);
+        let (chunk_position, local_position) = coords::split(position);
         if let Some(chunk) = self.chunks.get(&chunk_position) {
-            let position = Position::new(position.x % CHUNK_SIZE_X, position.y % CHUNK_SIZE_Y, position.z % CHUNK_SIZE_Z);
-            chunk.get_block(&position)
+            chunk.get_block(&local_position)
         } else {
             BLOCK_TYPE_AIR
         }
     }
-}
\ No newline at end of file
+
+    pub fn get_block_light(&self, position: &Position) -> u8 {
+        let (chunk_position, local_position) = coords::split(position);
+        self.chunks.get(&chunk_position).map_or(0, |chunk| chunk.get_block_light(&local_position))
+    }
+
+    // Light is only kept for loaded chunks, returns false when the position is outside of them
+    pub fn set_block_light(&mut self, position: &Position, level: u8) -> bool {
+        self.set_light(position, |chunk, local_position| {
+            let changed = chunk.get_block_light(local_position) != level;
+            chunk.set_block_light(local_position, level);
+            changed
+        })
+    }
+
+    pub fn get_sky_light(&self, position: &Position) -> u8 {
+        let (chunk_position, local_position) = coords::split(position);
+        self.chunks.get(&chunk_position).map_or(0, |chunk| chunk.get_sky_light(&local_position))
+    }
+
+    pub fn set_sky_light(&mut self, position: &Position, level: u8) -> bool {
+        self.set_light(position, |chunk, local_position| {
+            let changed = chunk.get_sky_light(local_position) != level;
+            chunk.set_sky_light(local_position, level);
+            changed
+        })
+    }
+
+    fn set_light(&mut self, position: &Position, set: impl FnOnce(&mut Chunk, &Position) -> bool) -> bool {
+        let (chunk_position, local_position) = coords::split(position);
+        let chunk = match self.chunks.get_mut(&chunk_position) {
+            Some(chunk) => chunk,
+            None => return false
+        };
+
+        if set(chunk, &local_position) {
+            self.mark_border_dirty(&chunk_position, &local_position);
+        }
+        true
+    }
+
+    // World y of the highest opaque block in the loaded part of the column
+    pub fn surface_height(&self, x: i64, z: i64) -> Option<i64> {
+        let (chunk_position, local_position) = coords::split(&Position::new(x, 0, z));
+        let column = self.columns.get(&(chunk_position.x, chunk_position.z))?;
+
+        column.iter().rev().find_map(|chunk_y| {
+            let chunk = &self.chunks[&Position::new(chunk_position.x, *chunk_y, chunk_position.z)];
+            chunk.height(local_position.x, local_position.z).map(|height| chunk_y * CHUNK_SIZE_Y + height)
+        })
+    }
+
+    // World y of the lowest block in the loaded part of the column
+    pub fn column_bottom(&self, x: i64, z: i64) -> Option<i64> {
+        let chunk_position = coords::world_to_chunk(&Position::new(x, 0, z));
+        let column = self.columns.get(&(chunk_position.x, chunk_position.z))?;
+        column.iter().next().map(|chunk_y| chunk_y * CHUNK_SIZE_Y)
+    }
+
+    pub fn set_block(&mut self, position: &Position, block_type: u16) {
+        let (chunk_position, local_position) = coords::split(position);
+        if !self.chunks.contains_key(&chunk_position) {
+            self.add_chunk(Chunk::new(chunk_position, BLOCK_TYPE_AIR));
+        }
+
+        let chunk = self.chunks.get_mut(&chunk_position).unwrap();
+        if chunk.get_block(&local_position) == block_type {
+            return;
+        }
+
+        chunk.set_block(block_type, &local_position);
+        chunk.update_height(&local_position, |block| block_flag(&self.opaque, block));
+        self.modified_chunks.insert(chunk_position);
+        self.mark_border_dirty(&chunk_position, &local_position);
+    }
+
+    // Marks the chunk and every neighbour whose mesh can see the given block
+    fn mark_border_dirty(&mut self, chunk_position: &Position, local_position: &Position) {
+        let x_range = border_range(local_position.x, CHUNK_SIZE_X);
+        let y_range = border_range(local_position.y, CHUNK_SIZE_Y);
+        let z_range = border_range(local_position.z, CHUNK_SIZE_Z);
+        for x in x_range {
+            for y in y_range.clone() {
+                for z in z_range.clone() {
+                    self.mark_dirty(&chunk_position.offset(x, y, z));
+                }
+            }
+        }
+    }
+
+    pub fn mark_dirty(&mut self, chunk_position: &Position) {
+        if self.chunks.contains_key(chunk_position) {
+            self.dirty_chunks.insert(*chunk_position);
+        }
+    }
+
+    pub fn is_dirty(&self, chunk_position: &Position) -> bool {
+        self.dirty_chunks.contains(chunk_position)
+    }
+
+    pub fn take_dirty_chunks(&mut self) -> Vec<Position> {
+        self.dirty_chunks.drain().collect()
+    }
+}
+
+// Per block flags taken from the registry. Without a registry every block but air has them.
+fn block_flag(table: &[bool], block: u16) -> bool {
+    if table.is_empty() {
+        block != BLOCK_TYPE_AIR
+    } else {
+        table.get(block as usize).copied().unwrap_or(false)
+    }
+}
+
+// Chunk offsets whose meshes can see a block at the given local coordinate
+fn border_range(local: i64, size: i64) -> std::ops::RangeInclusive<i64> {
+    let start = if local == 0 { -1 } else { 0 };
+    let end = if local == size - 1 { 1 } else { 0 };
+    start..=end
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::mem;
+    use super::*;
+    use crate::atlas::{AtlasConfig, TextureAtlas};
+    use crate::caves::CaveConfig;
+    use crate::light;
+    use crate::worldgen::{HeightmapGenerator, TerrainBlocks, TerrainConfig, WorldGenerator};
+
+    // Only the opaque layer, the blocks in these tests are all opaque
+    fn mesh(world: &World, registry: &BlockRegistry, mode: MeshingMode) -> BufferBuilder {
+        let snapshot = ChunkSnapshot::capture(world, &Position::default()).unwrap();
+        let mut meshes = ChunkBuilder::new(&snapshot, registry).with_mode(mode).build();
+        mem::take(meshes.layer_mut(RenderLayer::Opaque))
+    }
+
+    fn quad_count(mesh: &BufferBuilder) -> usize {
+        mesh.indices().len() / 6
+    }
+
+    // Unit squares covered by the quads. The winding tells front faces apart from back faces on the same plane.
+    fn coverage(mesh: &BufferBuilder) -> HashSet<([i64; 3], [i64; 3])> {
+        let mut cells = HashSet::new();
+
+        for quad in mesh.vertices().chunks(4) {
+            let positions = quad.iter().map(|vertex| vertex.position()).collect::<Vec<_>>();
+            let normal = (positions[1] - positions[0]).cross(positions[2] - positions[0]).normalize().round();
+            let min = positions.iter().fold(Vec3::splat(f32::MAX), |min, position| min.min(*position));
+            let max = positions.iter().fold(Vec3::splat(f32::MIN), |max, position| max.max(*position));
+            let size = max - min;
+
+            // The texture has to repeat once per block across the quad
+            let uv_min = quad.iter().fold(Vec2::splat(f32::MAX), |min, vertex| min.min(vertex.uv()));
+            let uv_max = quad.iter().fold(Vec2::splat(f32::MIN), |max, vertex| max.max(vertex.uv()));
+            let mut extents = [size.x, size.y, size.z].into_iter().filter(|extent| *extent > 0.0).collect::<Vec<_>>();
+            let mut uv_extents = vec![uv_max.x - uv_min.x, uv_max.y - uv_min.y];
+            extents.sort_by(f32::total_cmp);
+            uv_extents.sort_by(f32::total_cmp);
+            assert_eq!(extents, uv_extents);
+
+            let range = |axis: usize| {
+                let start = min[axis] as i64;
+                start..(start + (size[axis] as i64).max(1))
+            };
+            for x in range(0) {
+                for y in range(1) {
+                    for z in range(2) {
+                        let key = ([normal.x as i64, normal.y as i64, normal.z as i64], [x, y, z]);
+                        assert!(cells.insert(key), "Quads overlap at {:?}", key);
+                    }
+                }
+            }
+        }
+
+        cells
+    }
+
+    // Chunks from -1 to 1 on every axis with nothing marked dirty yet, leaving out the given ones
+    fn grid_without(missing: &[Position]) -> World {
+        let mut world = World::new();
+        for x in -1..=1 {
+            for y in -1..=1 {
+                for z in -1..=1 {
+                    if !missing.contains(&Position::new(x, y, z)) {
+                        world.add_chunk(Chunk::new(Position::new(x, y, z), BLOCK_TYPE_AIR));
+                    }
+                }
+            }
+        }
+        world.take_dirty_chunks();
+        world
+    }
+
+    fn dirty_chunks(world: &mut World) -> HashSet<Position> {
+        world.take_dirty_chunks().into_iter().collect()
+    }
+
+    #[test]
+    fn interior_edits_only_mark_their_own_chunk() {
+        let mut world = grid_without(&[]);
+        world.set_block(&Position::new(5, 7, 9), 1);
+
+        assert_eq!(dirty_chunks(&mut world), HashSet::from([Position::new(0, 0, 0)]));
+        assert!(world.is_modified(&Position::new(0, 0, 0)));
+    }
+
+    #[test]
+    fn border_edits_mark_the_neighbours_that_can_see_them() {
+        let mut world = grid_without(&[]);
+        world.set_block(&Position::new(15, 7, 9), 1);
+        assert_eq!(dirty_chunks(&mut world), HashSet::from([Position::new(0, 0, 0), Position::new(1, 0, 0)]));
+
+        world.set_block(&Position::new(-16, 7, 9), 1);
+        assert_eq!(dirty_chunks(&mut world), HashSet::from([Position::new(-1, 0, 0)]));
+    }
+
+    #[test]
+    fn corner_edits_mark_the_loaded_diagonal_neighbours() {
+        let mut world = grid_without(&[Position::new(-1, -1, -1), Position::new(-1, 0, -1)]);
+        world.set_block(&Position::new(0, 0, 0), 1);
+
+        let expected = [(0, 0, 0), (-1, 0, 0), (0, -1, 0), (0, 0, -1), (-1, -1, 0), (0, -1, -1)]
+            .map(|(x, y, z)| Position::new(x, y, z));
+        assert_eq!(dirty_chunks(&mut world), HashSet::from(expected));
+    }
+
+    #[test]
+    fn setting_the_same_block_marks_nothing() {
+        let mut world = grid_without(&[]);
+        world.set_block(&Position::new(15, 15, 15), BLOCK_TYPE_AIR);
+        assert!(dirty_chunks(&mut world).is_empty());
+
+        world.set_block(&Position::new(15, 15, 15), 1);
+        world.take_dirty_chunks();
+        world.set_block(&Position::new(15, 15, 15), 1);
+        assert!(dirty_chunks(&mut world).is_empty());
+    }
+
+    #[test]
+    fn taking_dirty_chunks_drains_them() {
+        let mut world = grid_without(&[]);
+        world.set_block(&Position::new(3, 3, 3), 1);
+
+        assert!(world.is_dirty(&Position::new(0, 0, 0)));
+        assert_eq!(world.take_dirty_chunks(), [Position::new(0, 0, 0)]);
+        assert!(!world.is_dirty(&Position::new(0, 0, 0)));
+        assert!(world.take_dirty_chunks().is_empty());
+    }
+
+    #[test]
+    fn full_chunk_collapses_to_six_quads() {
+        let registry = BlockRegistry::load("blocks.ron");
+        let mut world = World::new();
+        world.add_chunk(Chunk::new(Position::default(), registry.id("stone").unwrap()));
+
+        let per_face = mesh(&world, &registry, MeshingMode::PerFace);
+        let greedy = mesh(&world, &registry, MeshingMode::Greedy);
+
+        assert_eq!(quad_count(&per_face), 1536);
+        assert_eq!(quad_count(&greedy), 6);
+        assert_eq!(coverage(&per_face), coverage(&greedy));
+    }
+
+    #[test]
+    fn different_blocks_are_not_merged() {
+        let registry = BlockRegistry::load("blocks.ron");
+        let stone = registry.id("stone").unwrap();
+        let dirt = registry.id("dirt").unwrap();
+
+        let mut world = World::new();
+        for x in 0..4 {
+            for z in 0..4 {
+                world.set_block(&Position::new(x, 0, z), if (x + z) % 2 == 0 { stone } else { dirt });
+            }
+        }
+
+        let per_face = mesh(&world, &registry, MeshingMode::PerFace);
+        let greedy = mesh(&world, &registry, MeshingMode::Greedy);
+
+        // Top and bottom stay a checkerboard, each side row alternates too
+        assert_eq!(quad_count(&greedy), quad_count(&per_face));
+        assert_eq!(coverage(&per_face), coverage(&greedy));
+    }
+
+    #[test]
+    fn greedy_mesh_covers_generated_terrain() {
+        let registry = BlockRegistry::load("blocks.ron");
+        let generator = HeightmapGenerator::new(9, TerrainConfig::default(), TerrainBlocks::from_registry(&registry))
+            .with_caves(CaveConfig::default());
+
+        let mut world = World::new();
+        world.add_chunk(generator.generate_chunk(&Position::default()));
+        for face in Face::ALL {
+            let normal = face.normal();
+            world.add_chunk(generator.generate_chunk(&normal));
+        }
+
+        let per_face = mesh(&world, &registry, MeshingMode::PerFace);
+        let greedy = mesh(&world, &registry, MeshingMode::Greedy);
+
+        // Occlusion keeps faces along steps and cave walls apart, flat ground still merges
+        assert!(quad_count(&greedy) < quad_count(&per_face), "{} vs {}", quad_count(&greedy), quad_count(&per_face));
+        assert_eq!(coverage(&per_face), coverage(&greedy));
+    }
+
+    #[test]
+    fn occlusion_darkens_corners_next_to_blocks() {
+        let registry = BlockRegistry::load("blocks.ron");
+        let stone = registry.id("stone").unwrap();
+
+        let mut world = World::new();
+        for x in 0..4 {
+            for z in 0..4 {
+                world.set_block(&Position::new(x, 0, z), stone);
+            }
+        }
+        world.set_block(&Position::new(1, 1, 1), stone);
+
+        for mode in [MeshingMode::PerFace, MeshingMode::Greedy] {
+            let mesh = mesh(&world, &registry, mode);
+            let corner_ao = |corner: Vec3, plane: fn(&[Vec3]) -> bool| mesh.vertices().chunks(4)
+                .filter(|quad| plane(&quad.iter().map(|vertex| vertex.position()).collect::<Vec<_>>()))
+                .flat_map(|quad| quad.iter())
+                .filter(|vertex| vertex.position() == corner)
+                .map(|vertex| vertex.ao())
+                .collect::<Vec<_>>();
+
+            // The floor is darkened where it meets the block, the bottom edge of the block where it meets the floor
+            let floor = corner_ao(Vec3::new(1.0, 1.0, 1.0), |quad| quad.iter().all(|position| position.y == 1.0));
+            let wall = corner_ao(Vec3::new(1.0, 1.0, 1.0), |quad| quad.iter().all(|position| position.x == 1.0));
+            let open = corner_ao(Vec3::new(4.0, 1.0, 4.0), |quad| quad.iter().all(|position| position.y == 1.0));
+            assert!(!floor.is_empty() && floor.iter().all(|ao| *ao == 2.0 / 3.0), "{:?}", floor);
+            assert!(!wall.is_empty() && wall.iter().all(|ao| *ao == 1.0 / 3.0), "{:?}", wall);
+            assert!(!open.is_empty() && open.iter().all(|ao| *ao == 1.0), "{:?}", open);
+
+            // Quads are always split along the darker diagonal
+            for quad in mesh.vertices().chunks(4) {
+                assert!(quad[0].ao() + quad[2].ao() <= quad[1].ao() + quad[3].ao());
+            }
+
+            assert_eq!(coverage(&mesh), coverage(&self::mesh(&world, &registry, MeshingMode::PerFace)));
+        }
+    }
+
+    #[test]
+    fn light_is_baked_into_vertices() {
+        let registry = BlockRegistry::load("blocks.ron");
+        let stone = registry.id("stone").unwrap();
+
+        let mut world = World::new();
+        world.add_chunk(Chunk::new(Position::default(), BLOCK_TYPE_AIR));
+        for x in 0..CHUNK_SIZE_X {
+            for z in 0..CHUNK_SIZE_Z {
+                light::set_block(&mut world, &registry, &Position::new(x, 0, z), stone);
+            }
+        }
+        light::set_block(&mut world, &registry, &Position::new(2, 1, 2), registry.id("torch").unwrap());
+
+        let mesh = mesh(&world, &registry, MeshingMode::Greedy);
+        let floor_light = |x: f32, z: f32| mesh.vertices().iter()
+            .filter(|vertex| vertex.position() == Vec3::new(x, 1.0, z))
+            .map(|vertex| vertex.light())
+            .fold(0.0, f32::max);
+
+        // The corner under the torch averages 14, 13, 13 and 12, far corners stay dark
+        assert_eq!(floor_light(2.0, 2.0), 52.0 / 60.0);
+        assert!(floor_light(8.0, 8.0) < floor_light(4.0, 4.0));
+        assert_eq!(floor_light(16.0, 16.0), 0.0);
+    }
+
+    #[test]
+    fn heightmaps_follow_the_highest_opaque_block() {
+        let registry = BlockRegistry::load("blocks.ron");
+        let stone = registry.id("stone").unwrap();
+        let mut world = World::with_registry(&registry);
+        world.add_chunk(Chunk::new(Position::new(-1, -1, -1), stone));
+        world.add_chunk(Chunk::new(Position::new(-1, 0, -1), BLOCK_TYPE_AIR));
+
+        assert_eq!(world.surface_height(-5, -5), Some(-1));
+        assert_eq!(world.surface_height(5, 5), None);
+
+        world.set_block(&Position::new(-5, 12, -5), stone);
+        world.set_block(&Position::new(-5, 3, -5), stone);
+        assert_eq!(world.surface_height(-5, -5), Some(12));
+
+        // Leaves let light through and do not count as the surface
+        world.set_block(&Position::new(-5, 12, -5), registry.id("leaves").unwrap());
+        assert_eq!(world.surface_height(-5, -5), Some(3));
+        world.set_block(&Position::new(-5, 3, -5), BLOCK_TYPE_AIR);
+        assert_eq!(world.surface_height(-5, -5), Some(-1));
+
+        // Blocks in chunks that were not loaded yet bring their chunk in
+        world.set_block(&Position::new(-5, 40, -5), stone);
+        assert_eq!(world.surface_height(-5, -5), Some(40));
+        world.remove_chunk(&Position::new(-1, 2, -1));
+        assert_eq!(world.surface_height(-5, -5), Some(-1));
+        assert_eq!(world.column_bottom(-5, -5), Some(-16));
+    }
+
+    #[test]
+    fn faces_use_the_tile_of_their_block_and_side() {
+        let registry = BlockRegistry::load("blocks.ron");
+        let atlas = TextureAtlas::load("textures", AtlasConfig::default());
+        let uvs = BlockUvs::new(&registry, &atlas);
+        let mut world = World::new();
+        world.add_chunk(Chunk::default());
+        world.set_block(&Position::new(2, 2, 2), registry.id("grass").unwrap());
+        world.set_block(&Position::new(3, 2, 2), registry.id("grass").unwrap());
+
+        let snapshot = ChunkSnapshot::capture(&world, &Position::default()).unwrap();
+        let meshes = ChunkBuilder::new(&snapshot, &registry).with_uvs(&uvs).build();
+
+        for quad in meshes.layer(RenderLayer::Opaque).vertices().chunks(4) {
+            let normal = (quad[2].position() - quad[0].position()).cross(quad[1].position() - quad[0].position()).normalize().round();
+            let expected = match normal.y as i64 {
+                1 => "grass_top",
+                -1 => "dirt",
+                _ => "grass_side"
+            };
+            assert!(quad.iter().all(|vertex| vertex.tile() == atlas.get(expected).unwrap().to_vec4()), "{:?} should use {}", normal, expected);
+        }
+    }
+
+    #[test]
+    fn layers_get_separate_meshes() {
+        let registry = BlockRegistry::load("blocks.ron");
+        let mut world = World::with_registry(&registry);
+        world.add_chunk(Chunk::default());
+        world.set_block(&Position::new(2, 2, 2), registry.id("stone").unwrap());
+        world.set_block(&Position::new(5, 2, 2), registry.id("leaves").unwrap());
+        world.set_block(&Position::new(6, 2, 2), registry.id("leaves").unwrap());
+        world.set_block(&Position::new(9, 2, 2), registry.id("water").unwrap());
+        world.set_block(&Position::new(10, 2, 2), registry.id("water").unwrap());
+        world.set_block(&Position::new(11, 2, 2), registry.id("stone").unwrap());
+
+        let snapshot = ChunkSnapshot::capture(&world, &Position::default()).unwrap();
+        let meshes = ChunkBuilder::new(&snapshot, &registry).with_mode(MeshingMode::PerFace).build();
+
+        // Stone shows its face towards the water, leaves keep the faces between them, water hides its own
+        assert_eq!(quad_count(meshes.layer(RenderLayer::Opaque)), 6 + 6);
+        assert_eq!(quad_count(meshes.layer(RenderLayer::Cutout)), 12);
+        assert_eq!(quad_count(meshes.layer(RenderLayer::Translucent)), 9);
+    }
+
+    #[test]
+    fn fluids_are_meshed_at_the_height_of_their_level() {
+        let registry = BlockRegistry::load("blocks.ron");
+        let water = registry.id("water").unwrap();
+        let mut world = World::with_registry(&registry);
+        world.add_chunk(Chunk::default());
+        world.set_block(&Position::new(2, 2, 2), water);
+        world.set_block(&Position::new(3, 2, 2), registry.fluid_block(water, 4).unwrap());
+        world.set_block(&Position::new(8, 2, 2), registry.fluid_block(water, 1).unwrap());
+        world.set_block(&Position::new(8, 3, 2), water);
+
+        let snapshot = ChunkSnapshot::capture(&world, &Position::default()).unwrap();
+        for mode in [MeshingMode::PerFace, MeshingMode::Greedy] {
+            let meshes = ChunkBuilder::new(&snapshot, &registry).with_mode(mode).build();
+            let quads = meshes.layer(RenderLayer::Translucent).vertices().chunks(4).map(|quad| {
+                let min = quad.iter().fold(Vec3::splat(f32::MAX), |min, vertex| min.min(vertex.position()));
+                let max = quad.iter().fold(Vec3::splat(f32::MIN), |max, vertex| max.max(vertex.position()));
+                (min, max, quad)
+            }).collect::<Vec<_>>();
+            let find = |min: Vec3, max: Vec3| quads.iter().find(|quad| quad.0 == min && quad.1 == max).map(|quad| quad.2);
+
+            // Sources and levels sit below the top of their block, the source shows the step down to the level
+            assert!(find(Vec3::new(2.0, 2.875, 2.0), Vec3::new(3.0, 2.875, 3.0)).is_some());
+            assert!(find(Vec3::new(3.0, 2.4375, 2.0), Vec3::new(4.0, 2.4375, 3.0)).is_some());
+            let step = find(Vec3::new(3.0, 2.4375, 2.0), Vec3::new(3.0, 2.875, 3.0)).expect("Missing step between levels");
+            assert!(step.iter().all(|vertex| vertex.uv().y == 1.0 - (vertex.position().y - 2.0)));
+            assert!(find(Vec3::new(3.0, 2.0, 2.0), Vec3::new(3.0, 2.875, 3.0)).is_none());
+
+            // Under more water the block is full, no surface between the two
+            assert!(find(Vec3::new(8.0, 2.0, 2.0), Vec3::new(8.0, 3.0, 3.0)).is_some());
+            assert!(find(Vec3::new(8.0, 3.0, 2.0), Vec3::new(9.0, 3.0, 3.0)).is_none());
+            assert!(find(Vec3::new(8.0, 3.875, 2.0), Vec3::new(9.0, 3.875, 3.0)).is_some());
+        }
+    }
+
+    #[test]
+    fn rays_find_the_first_block_across_chunks() {
+        let registry = BlockRegistry::load("blocks.ron");
+        let stone = registry.id("stone").unwrap();
+        let mut world = World::with_registry(&registry);
+        for x in -2..=1 {
+            world.add_chunk(Chunk::new(Position::new(x, -1, 0), BLOCK_TYPE_AIR));
+        }
+        world.set_block(&Position::new(-20, -3, 4), stone);
+        world.set_block(&Position::new(-21, -3, 4), stone);
+        world.set_block(&Position::new(-25, -3, 4), registry.id("water").unwrap());
+        world.set_block(&Position::new(-30, -3, 4), stone);
+
+        // Straight along -x from the next chunk over, through water and into the first stone
+        let hit = world.raycast(Vec3::new(5.5, -2.5, 4.5), Vec3::NEG_X, 40.0).unwrap();
+        assert_eq!(hit.position, Position::new(-20, -3, 4));
+        assert_eq!(hit.normal, Position::new(1, 0, 0));
+        assert!((hit.distance - 24.5).abs() < 1e-4);
+        assert_eq!(world.raycast(Vec3::new(5.5, -2.5, 4.5), Vec3::NEG_X, 24.0), None);
+
+        let hit = world.raycast(Vec3::new(-24.5, -2.5, 4.5), Vec3::NEG_X, 40.0).unwrap();
+        assert_eq!(hit.position, Position::new(-30, -3, 4));
+
+        // Diagonally down onto the top of a block
+        let origin = Vec3::new(-18.0, 0.5, 4.5);
+        let hit = world.raycast(origin, Vec3::new(-1.0, -2.0, 0.0), 10.0).unwrap();
+        assert_eq!(hit.position, Position::new(-20, -3, 4));
+        assert_eq!(hit.normal, Position::new(0, 1, 0));
+        let point = origin + Vec3::new(-1.0, -2.0, 0.0).normalize() * hit.distance;
+        assert!((point.y + 2.0).abs() < 1e-4 && point.x > -20.0 && point.x < -19.0);
+
+        // Starting inside a block hits it right away
+        let hit = world.raycast(Vec3::new(-19.5, -2.5, 4.5), Vec3::Y, 10.0).unwrap();
+        assert_eq!((hit.position, hit.normal, hit.distance), (Position::new(-20, -3, 4), Position::default(), 0.0));
+        assert_eq!(world.raycast(Vec3::ZERO, Vec3::ZERO, 10.0), None);
+    }
+
+    #[test]
+    fn rays_without_an_end_give_up() {
+        let mut world = World::new();
+        world.add_chunk(Chunk::new(Position::default(), BLOCK_TYPE_AIR));
+
+        // Into empty space that is never loaded
+        for max_distance in [f32::NAN, f32::INFINITY] {
+            assert_eq!(world.raycast(Vec3::splat(8.5), Vec3::Y, max_distance), None);
+        }
+        assert_eq!(world.raycast(Vec3::new(f32::NAN, 8.5, 8.5), Vec3::Y, 10.0), None);
+        assert_eq!(world.raycast(Vec3::new(8.5, f32::INFINITY, 8.5), Vec3::NEG_Y, 10.0), None);
+    }
+}
@@ -0,0 +1,307 @@
+use noise::{Fbm, MultiFractal, NoiseFn, Perlin};
+use crate::biome::{Biome, BiomeMap};
+use crate::block::BlockRegistry;
+use crate::caves::{CaveCarver, CaveConfig};
+use crate::coords;
+use crate::structures::StructurePlacer;
+use crate::world::{Chunk, Position, BLOCK_TYPE_AIR, CHUNK_SIZE_X, CHUNK_SIZE_Y, CHUNK_SIZE_Z};
+
+// Generators are shared with the worker threads
+pub trait WorldGenerator: Send + Sync {
+    fn seed(&self) -> u32;
+
+    fn generate(&self, chunk: &mut Chunk);
+
+    fn generate_chunk(&self, position: &Position) -> Chunk {
+        let mut chunk = Chunk::new(*position, BLOCK_TYPE_AIR);
+        self.generate(&mut chunk);
+        chunk
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TerrainConfig {
+    pub base_height: f64,
+    pub amplitude: f64,
+    pub frequency: f64,
+    pub octaves: usize,
+    pub sea_level: i64,
+    pub dirt_depth: i64
+}
+
+impl Default for TerrainConfig {
+    fn default() -> Self {
+        Self {
+            base_height: 8.0,
+            amplitude: 12.0,
+            frequency: 1.0 / 128.0,
+            octaves: 5,
+            sea_level: 4,
+            dirt_depth: 3
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct SurfaceLayers {
+    pub surface: u16,
+    pub filler: u16,
+    pub filler_depth: i64
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct TerrainBlocks {
+    pub stone: u16,
+    pub dirt: u16,
+    pub grass: u16,
+    pub water: u16
+}
+
+impl TerrainBlocks {
+    pub fn from_registry(registry: &BlockRegistry) -> Self {
+        let id = |name: &str| registry.id(name).unwrap_or_else(|| panic!("Block registry has no {}", name));
+
+        Self {
+            stone: id("stone"),
+            dirt: id("dirt"),
+            grass: id("grass"),
+            water: id("water")
+        }
+    }
+}
+
+pub struct HeightmapGenerator {
+    seed: u32,
+    config: TerrainConfig,
+    blocks: TerrainBlocks,
+    height_noise: Fbm<Perlin>,
+    biomes: Option<BiomeMap>,
+    caves: Option<CaveCarver>,
+    structures: Option<StructurePlacer>
+}
+
+impl HeightmapGenerator {
+    pub fn new(seed: u32, config: TerrainConfig, blocks: TerrainBlocks) -> Self {
+        let height_noise = Fbm::<Perlin>::new(seed)
+            .set_octaves(config.octaves)
+            .set_frequency(config.frequency);
+
+        Self {
+            seed,
+            config,
+            blocks,
+            height_noise,
+            biomes: None,
+            caves: None,
+            structures: None
+        }
+    }
+
+    pub fn with_biomes(mut self, biomes: BiomeMap) -> Self {
+        self.biomes = Some(biomes);
+        self
+    }
+
+    pub fn with_caves(mut self, config: CaveConfig) -> Self {
+        self.caves = Some(CaveCarver::new(self.seed, config, vec![self.blocks.water]));
+        self
+    }
+
+    pub fn with_structures(mut self, structures: StructurePlacer) -> Self {
+        self.structures = Some(structures);
+        self
+    }
+
+    pub fn config(&self) -> &TerrainConfig {
+        &self.config
+    }
+
+    pub fn biome_at(&self, position: &Position) -> Option<&Biome> {
+        self.biomes.as_ref().map(|biomes| biomes.biome_at(position))
+    }
+
+    pub fn surface_height(&self, x: i64, z: i64) -> i64 {
+        let (base_height, amplitude) = match &self.biomes {
+            Some(biomes) => biomes.terrain_shape(x, z),
+            None => (self.config.base_height, self.config.amplitude)
+        };
+
+        let noise = self.height_noise.get([x as f64, z as f64]);
+        (base_height + noise * amplitude).floor() as i64
+    }
+
+    pub fn surface_layers(&self, x: i64, z: i64) -> SurfaceLayers {
+        match &self.biomes {
+            Some(biomes) => {
+                let biome = biomes.biome_at_column(x, z);
+                SurfaceLayers {
+                    surface: biome.surface_block,
+                    filler: biome.filler_block,
+                    filler_depth: biome.filler_depth
+                }
+            }
+            None => SurfaceLayers {
+                surface: self.blocks.grass,
+                filler: self.blocks.dirt,
+                filler_depth: self.config.dirt_depth
+            }
+        }
+    }
+
+    // Block the terrain and cave passes produce at a position, before any structures are placed
+    pub fn terrain_block_at(&self, position: &Position) -> u16 {
+        let surface_height = self.surface_height(position.x, position.z);
+        let block = self.column_block(position.y, surface_height, &self.surface_layers(position.x, position.z));
+
+        match &self.caves {
+            Some(caves) if caves.carves(block, position) => BLOCK_TYPE_AIR,
+            _ => block
+        }
+    }
+
+    pub fn column_block(&self, y: i64, surface_height: i64, layers: &SurfaceLayers) -> u16 {
+        if y > surface_height {
+            if y <= self.config.sea_level { self.blocks.water } else { BLOCK_TYPE_AIR }
+        } else if y == surface_height && surface_height >= self.config.sea_level {
+            layers.surface
+        } else if y > surface_height - layers.filler_depth {
+            layers.filler
+        } else {
+            self.blocks.stone
+        }
+    }
+}
+
+impl WorldGenerator for HeightmapGenerator {
+    fn seed(&self) -> u32 {
+        self.seed
+    }
+
+    fn generate(&self, chunk: &mut Chunk) {
+        let origin = coords::chunk_origin(chunk.position());
+
+        for x in 0..CHUNK_SIZE_X {
+            for z in 0..CHUNK_SIZE_Z {
+                let surface_height = self.surface_height(origin.x + x, origin.z + z);
+                let layers = self.surface_layers(origin.x + x, origin.z + z);
+
+                for y in 0..CHUNK_SIZE_Y {
+                    let block = self.column_block(origin.y + y, surface_height, &layers);
+                    chunk.set_block(block, &Position::new(x, y, z));
+                }
+            }
+        }
+
+        if let Some(caves) = &self.caves {
+            caves.carve(chunk);
+        }
+
+        if let Some(structures) = &self.structures {
+            structures.decorate(chunk, self);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generator(seed: u32) -> HeightmapGenerator {
+        let registry = BlockRegistry::load("blocks.ron");
+        HeightmapGenerator::new(seed, TerrainConfig::default(), TerrainBlocks::from_registry(&registry))
+    }
+
+    fn blocks_of(chunk: &Chunk) -> Vec<u16> {
+        let mut blocks = Vec::new();
+        for x in 0..CHUNK_SIZE_X {
+            for y in 0..CHUNK_SIZE_Y {
+                for z in 0..CHUNK_SIZE_Z {
+                    blocks.push(chunk.get_block(&Position::new(x, y, z)));
+                }
+            }
+        }
+        blocks
+    }
+
+    #[test]
+    fn same_seed_generates_same_chunks() {
+        let first = generator(1234);
+        let second = generator(1234);
+
+        for position in [Position::new(0, 0, 0), Position::new(-3, -1, 7), Position::new(12, 1, -40)] {
+            assert_eq!(blocks_of(&first.generate_chunk(&position)), blocks_of(&second.generate_chunk(&position)));
+        }
+    }
+
+    #[test]
+    fn different_seeds_generate_different_terrain() {
+        let position = Position::new(2, 0, 2);
+        assert_ne!(blocks_of(&generator(1).generate_chunk(&position)), blocks_of(&generator(2).generate_chunk(&position)));
+    }
+
+    #[test]
+    fn columns_are_layered_from_the_surface() {
+        let generator = generator(42);
+        let blocks = TerrainBlocks::from_registry(&BlockRegistry::load("blocks.ron"));
+        let config = TerrainConfig::default();
+        let chunk = generator.generate_chunk(&Position::new(-1, 0, 3));
+
+        for x in 0..CHUNK_SIZE_X {
+            for z in 0..CHUNK_SIZE_Z {
+                let world = coords::local_to_world(chunk.position(), &Position::new(x, 0, z));
+                let surface_height = generator.surface_height(world.x, world.z);
+
+                for y in 0..CHUNK_SIZE_Y {
+                    let expected = if y > surface_height {
+                        if y <= config.sea_level { blocks.water } else { BLOCK_TYPE_AIR }
+                    } else if y == surface_height && y >= config.sea_level {
+                        blocks.grass
+                    } else if y > surface_height - config.dirt_depth {
+                        blocks.dirt
+                    } else {
+                        blocks.stone
+                    };
+
+                    assert_eq!(chunk.get_block(&Position::new(x, y, z)), expected, "{} {} {}", x, y, z);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn deep_chunks_are_solid_stone() {
+        let generator = generator(7);
+        let chunk = generator.generate_chunk(&Position::new(5, -4, -5));
+
+        assert!(chunk.is_uniform());
+        assert_eq!(chunk.get_block(&Position::new(3, 3, 3)), generator.blocks.stone);
+    }
+
+    // Pinned output of a known chunk, any change to the noise, the config defaults or the layering shows up here.
+    // Regenerate the numbers only when the terrain is meant to change.
+    #[test]
+    fn known_chunk_matches_its_golden_contents() {
+        let generator = generator(5);
+        let chunk = generator.generate_chunk(&Position::new(0, 0, 0));
+        let [air, stone, dirt, grass, water] = [0, 1, 2, 3, 4];
+
+        let columns = [
+            ((0, 0), 8, [stone, stone, stone, stone, stone, stone, dirt, dirt, grass, air]),
+            ((3, 11), 5, [stone, stone, stone, dirt, dirt, grass, air, air, air, air]),
+            ((8, 8), 4, [stone, stone, dirt, dirt, grass, air, air, air, air, air]),
+            ((15, 2), 1, [dirt, dirt, water, water, water, air, air, air, air, air]),
+            ((12, 15), 2, [dirt, dirt, dirt, water, water, air, air, air, air, air])
+        ];
+        for ((x, z), height, column) in columns {
+            assert_eq!(generator.surface_height(x, z), height, "Surface of {} {}", x, z);
+            for (y, block) in column.iter().enumerate() {
+                assert_eq!(chunk.get_block(&Position::new(x, y as i64, z)), *block, "{} {} {}", x, y, z);
+            }
+            assert!((10..CHUNK_SIZE_Y).all(|y| chunk.get_block(&Position::new(x, y, z)) == air));
+        }
+
+        // FNV-1a with one step per block id, in x, y, z order
+        let hash = blocks_of(&chunk).iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, block| (hash ^ *block as u64).wrapping_mul(0x100_0000_01b3));
+        assert_eq!(hash, 0x6d3f_e24e_4a75_5809);
+    }
+}